@@ -6,7 +6,7 @@ use matrix_app_services::{ Appservice, Config, Namespace };
 async fn main() -> anyhow::Result<()> {
     dotenv::dotenv().ok();
     let config = Config::builder("matrix-scratchpad")
-        .homeserver("dax.gay")
+        .server_name("dax.gay")
         .proxy_ports(9000..10000)
         .sender_localpart("matrix-scratchpad")
         .url("http://192.168.1.30:21528")
@@ -16,7 +16,9 @@ async fn main() -> anyhow::Result<()> {
         .namespace(Namespace::alias("#.*"))
         .namespace(Namespace::room("#.*"))
         .local_address(([0,0,0,0], 21528))
-        .build();
+        .build()
+        .discover()
+        .await?;
     let service = Appservice::new(config)?;
     std::fs::write("registration.yaml", service.config().registration_yaml().unwrap()).unwrap();
     service.serve();
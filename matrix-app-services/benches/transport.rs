@@ -0,0 +1,89 @@
+//! Compares round-trip latency of the proxy transports. Both go through the internal proxy, so this measures the cost
+//! of the hop to it, not of the proxy itself (see [ProxyTransport]).
+//!
+//! The homeserver is a stub answering `/versions` & `/whoami` on loopback, so only the transport between
+//! virtual clients and the proxy differs between runs and no homeserver is needed. Set `BENCH_ITERATIONS` to change the sample size.
+//!
+//! Run with `cargo bench --bench transport`.
+
+use std::{ net::SocketAddr, time::{ Duration, Instant } };
+
+use axum::{ routing::get, Json, Router };
+use matrix_app_services::{ Appservice, Config, Namespace, ProxyTransport };
+
+const SERVER_NAME: &str = "bench.localhost";
+const SENDER_LOCALPART: &str = "bench";
+
+/// Serves the endpoints the benchmark needs, as cheaply as possible
+async fn serve_stub() -> anyhow::Result<SocketAddr> {
+    let router = Router::new()
+        .route("/_matrix/client/versions", get(|| async {
+            Json(serde_json::json!({ "versions": ["v1.11"] }))
+        }))
+        .route("/_matrix/client/v3/account/whoami", get(|| async {
+            Json(serde_json::json!({ "user_id": format!("@{SENDER_LOCALPART}:{SERVER_NAME}") }))
+        }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    tokio::spawn(async move { axum::serve(listener, router).await });
+    Ok(address)
+}
+
+async fn run(homeserver: SocketAddr, transport: ProxyTransport, iterations: usize) -> anyhow::Result<Vec<Duration>> {
+    let config = Config::builder("matrix-app-services-bench")
+        // A host name, as the TLS transport reaches the proxy through its resolver
+        .homeserver(format!("http://localhost:{}", homeserver.port()))
+        .server_name(SERVER_NAME)
+        .sender_localpart(SENDER_LOCALPART)
+        .appservice_token("bench-as-token")
+        .homeserver_token("bench-hs-token")
+        .namespace(Namespace::user("@.*"))
+        .proxy_transport(transport)
+        .build();
+    let service = Appservice::new(config)?;
+    service.serve();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let client = service.build_service_client().build().await?;
+
+    // Warm up connection pools on both sides of the proxy
+    for _ in 0..5 {
+        client.whoami().await?;
+    }
+
+    let mut samples = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        let started = Instant::now();
+        client.whoami().await?;
+        samples.push(started.elapsed());
+    }
+
+    samples.sort();
+    Ok(samples)
+}
+
+fn report(name: &str, samples: &[Duration]) {
+    let mean = samples.iter().sum::<Duration>() / samples.len() as u32;
+    let percentile = |p: f64| samples[((samples.len() - 1) as f64 * p) as usize];
+    println!(
+        "{name:>5}: mean {mean:?}, p50 {:?}, p90 {:?}, p99 {:?} ({} requests)",
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+        samples.len()
+    );
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let iterations = std::env::var("BENCH_ITERATIONS").ok().and_then(|v| v.parse().ok()).unwrap_or(200);
+    let homeserver = serve_stub().await?;
+
+    let tls = run(homeserver, ProxyTransport::Tls, iterations).await?;
+    report("tls", &tls);
+
+    let unix = run(homeserver, ProxyTransport::Unix, iterations).await?;
+    report("unix", &unix);
+
+    Ok(())
+}
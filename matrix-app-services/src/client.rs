@@ -1,4 +1,4 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{collections::{HashMap, VecDeque}, net::SocketAddr, path::PathBuf, sync::Arc, time::{Duration, Instant}};
 
 use parking_lot::{Mutex, RwLock};
 use rcgen::CertifiedKey;
@@ -6,7 +6,13 @@ use reqwest::Certificate;
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::{ sync::OnceCell, task::JoinHandle };
 
-use crate::{types::{user::UserRecord, ProxyDirective, ProxyDirectiveTarget}, virtual_client::VirtualClientBuilder, Config, VirtualClient};
+use crate::{metrics::Metrics, servers::{appservice::TransactionHandler, breaker::CircuitBreaker, cache::ResponseCache, proxy::{ProxyLayers, ProxyListener}, scheduler::Scheduler}, types::{audit::audit_key, health::ReadinessChecks, AuditQuery, AuditRecord, user::{Profile, UserRecord}, MediaCacheEntry, HealthCheck, HomeserverStatus, ProxyDirective, ProxyDirectiveTarget, Readiness, Secret, Status}, virtual_client::VirtualClientBuilder, Config, ProxyTransport, VirtualClient};
+
+/// How long queued proxy directives wait for their request
+const PROXY_DIRECTIVE_TTL: Duration = Duration::from_secs(60);
+
+/// Directive batches queued per target, each with the time it was queued at
+type ProxyDirectiveQueues = HashMap<ProxyDirectiveTarget, VecDeque<(Instant, Vec<ProxyDirective>)>>;
 
 /// Appservice management instance
 #[derive(Debug, Clone)]
@@ -14,13 +20,25 @@ pub struct Appservice {
     config: Config,
     web_server: OnceCell<Arc<Mutex<JoinHandle<crate::Result<()>>>>>,
     proxy_server: OnceCell<Arc<Mutex<JoinHandle<crate::Result<()>>>>>,
+    admin_server: OnceCell<Arc<Mutex<JoinHandle<crate::Result<()>>>>>,
     proxy_port: u16,
+    proxy_socket: Option<Arc<tempfile::TempDir>>,
+    proxy_listener: Arc<Mutex<Option<ProxyListener>>>,
     certificate: String,
-    signing_key: String,
+    signing_key: Secret,
     state: sled::Db,
-    proxy_token: String,
+    proxy_token: Secret,
     clients: Arc<RwLock<HashMap<String, crate::VirtualClient>>>,
-    proxy_directives: Arc<RwLock<HashMap<ProxyDirectiveTarget, ProxyDirective>>>
+    proxy_directives: Arc<RwLock<ProxyDirectiveQueues>>,
+    metrics: Metrics,
+    scheduler: Option<Scheduler>,
+    response_cache: Option<ResponseCache>,
+    breaker: Option<CircuitBreaker>,
+    proxy_layers: Arc<RwLock<ProxyLayers>>,
+    registrations: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+    transaction_handler: Arc<RwLock<Option<TransactionHandler>>>,
+    /// Upload cache size, counted once & then tracked, as sled can only count by scanning
+    media_cache_len: Arc<Mutex<Option<usize>>>
 }
 
 impl Appservice {
@@ -39,36 +57,120 @@ impl Appservice {
         self.state::<V>(format!("custom/{}", collection.as_ref()))
     }
 
-    pub(crate) fn proxy_token(&self) -> String {
+    /// Gets the metrics collected by this Appservice
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    pub(crate) fn scheduler(&self) -> Option<Scheduler> {
+        self.scheduler.clone()
+    }
+
+    pub(crate) fn response_cache(&self) -> Option<ResponseCache> {
+        self.response_cache.clone()
+    }
+
+    pub(crate) fn breaker(&self) -> Option<CircuitBreaker> {
+        self.breaker.clone()
+    }
+
+    /// Wraps the internal proxy in a [tower::Layer], e.g. to inspect, modify or block outgoing requests.
+    /// The resolved [ProxiedEntity](crate::types::ProxiedEntity) is available as a request extension. Layers added later wrap earlier ones; only layers added before [Appservice::serve] apply.
+    pub fn proxy_layer<L>(&self, layer: L) -> ()
+    where
+        L: tower::Layer<axum::routing::Route> + Clone + Send + Sync + 'static,
+        L::Service: tower::Service<axum::extract::Request> + Clone + Send + Sync + 'static,
+        <L::Service as tower::Service<axum::extract::Request>>::Response: axum::response::IntoResponse + 'static,
+        <L::Service as tower::Service<axum::extract::Request>>::Error: Into<std::convert::Infallible> + 'static,
+        <L::Service as tower::Service<axum::extract::Request>>::Future: Send + 'static,
+    {
+        self.proxy_layers.write().push(Arc::new(move |router: axum::Router| router.layer(layer.clone())));
+    }
+
+    pub(crate) fn proxy_layers(&self) -> ProxyLayers {
+        self.proxy_layers.read().clone()
+    }
+
+    /// Handles the events the homeserver pushes to the appservice, replacing any previous handler. A transaction is only
+    /// acknowledged once `handler` succeeds, so the homeserver retries it on failure; acknowledged transactions aren't handled again.
+    /// Without a handler, pushed events are acknowledged & ignored.
+    pub fn on_transaction<F, Fut>(&self, handler: F) -> ()
+    where
+        F: Fn(Appservice, matrix_sdk::ruma::api::appservice::event::push_events::v1::Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        *self.transaction_handler.write() = Some(TransactionHandler(Arc::new(move |service, transaction| Box::pin(handler(service, transaction)))));
+    }
+
+    pub(crate) fn transaction_handler(&self) -> Option<TransactionHandler> {
+        self.transaction_handler.read().clone()
+    }
+
+    /// Gets the current status, including whether the homeserver is considered reachable
+    pub fn status(&self) -> Status {
+        Status {
+            homeserver: self.breaker.as_ref().map(|breaker| breaker.status()).unwrap_or(HomeserverStatus::Available),
+            virtual_clients: self.client_count(),
+        }
+    }
+
+    pub(crate) fn proxy_token(&self) -> Secret {
         self.proxy_token.clone()
     }
 
     /// Creates a new appservice from
     pub fn new(config: Config) -> crate::Result<Self> {
-        rustls::crypto::ring::default_provider().install_default().unwrap();
+        // Fail early rather than on the first request, see Config::discover
+        let _ = config.homeserver_url()?;
+        // Another Appservice (or the host application) may already have installed a provider
+        let _ = rustls::crypto::ring::default_provider().install_default();
         let CertifiedKey { cert, signing_key } = rcgen::generate_simple_self_signed(
             vec!["localhost".to_string()]
         )?;
         let cert = cert.pem();
-        let signing_key = signing_key.serialize_pem();
+        let signing_key = Secret::from(signing_key.serialize_pem());
 
-        let proxy_port = config.proxy_ports().pick();
+        // Bind now, so concurrent appservices can't race for the same port & failures surface here
+        let (proxy_port, proxy_socket, proxy_listener) = match config.proxy_transport() {
+            ProxyTransport::Tls => {
+                let listener = config.proxy_ports().bind().or_else(|e| Err(crate::Error::ProxyBind(e)))?;
+                let port = listener.local_addr().or_else(|e| Err(crate::Error::ProxyBind(e)))?.port();
+                (port, None, ProxyListener::Tcp(listener))
+            }
+            ProxyTransport::Unix => {
+                let dir = tempfile::Builder::new().prefix("matrix-app-services").tempdir()?;
+                let listener = ProxyListener::bind_unix(&dir.path().join("proxy.sock")).or_else(|e| Err(crate::Error::ProxyBind(e)))?;
+                (0, Some(Arc::new(dir)), listener)
+            }
+        };
         let state = match config.persist_state() {
             Some(path) => sled::open(path)?,
             None => sled::Config::new().temporary(true).open()?,
         };
 
+        let metrics = Metrics::new();
         let service = Appservice {
             config: config.clone(),
             web_server: OnceCell::new(),
             proxy_server: OnceCell::new(),
-            proxy_port: proxy_port.clone(),
+            admin_server: OnceCell::new(),
+            proxy_port,
+            proxy_socket,
+            proxy_listener: Arc::new(Mutex::new(Some(proxy_listener))),
             certificate: cert.clone(),
             signing_key: signing_key.clone(),
             state,
-            proxy_token: crate::generate_key(128),
+            proxy_token: Secret::from(crate::generate_key(128)),
             clients: Arc::new(RwLock::new(HashMap::new())),
-            proxy_directives: Arc::new(RwLock::new(HashMap::new()))
+            proxy_directives: Arc::new(RwLock::new(HashMap::new())),
+            scheduler: config.request_scheduling().map(|policy| Scheduler::new(policy, metrics.clone())),
+            response_cache: config.response_cache().map(|policy| ResponseCache::new(policy, metrics.clone())),
+            breaker: config.circuit_breaker().map(|policy| CircuitBreaker::new(policy, metrics.clone())),
+            proxy_layers: Arc::new(RwLock::new(ProxyLayers::default())),
+            registrations: Arc::new(Mutex::new(HashMap::new())),
+            transaction_handler: Arc::new(RwLock::new(None)),
+            media_cache_len: Arc::new(Mutex::new(None)),
+            metrics
         };
 
         Ok(service)
@@ -79,9 +181,12 @@ impl Appservice {
         if self.proxy_server.initialized() {
             return;
         }
+        let Some(proxy_listener) = self.proxy_listener.lock().take() else {
+            return;
+        };
         let config = self.config();
         let clonable_service = self.clone();
-        println!("Attempting to serve...");
+        tracing::info!(url = ?config.url(), proxy_port = self.proxy_port, "Starting appservice servers");
 
         if config.url().is_some() {
             self.web_server
@@ -99,28 +204,188 @@ impl Appservice {
                 .unwrap();
         }
 
+        if config.admin_address().is_some() {
+            self.admin_server
+                .set(
+                    Arc::new(
+                        Mutex::new(
+                            tokio::spawn(
+                                crate::servers::admin::serve_admin(
+                                    clonable_service.clone()
+                                )
+                            )
+                        )
+                    )
+                )
+                .unwrap();
+        }
+
         self.proxy_server
             .set(
                 Arc::new(
                     Mutex::new(
-                        tokio::spawn(
-                            crate::servers::proxy::serve_proxy(
-                                clonable_service.clone(),
-                                clonable_service.proxy_port.clone(),
-                                clonable_service.certificate.clone(),
-                                clonable_service.signing_key.clone()
-                            )
-                        )
+                        match proxy_listener {
+                            ProxyListener::Unix(listener) => tokio::spawn(
+                                crate::servers::proxy::serve_proxy_unix(clonable_service.clone(), listener)
+                            ),
+                            ProxyListener::Tcp(listener) => tokio::spawn(
+                                crate::servers::proxy::serve_proxy(
+                                    clonable_service.clone(),
+                                    listener,
+                                    clonable_service.certificate.clone(),
+                                    clonable_service.signing_key.clone()
+                                )
+                            ),
+                        }
                     )
                 )
             )
             .unwrap();
     }
 
+    /// Checks whether the proxy is listening, the homeserver is reachable and the state database is usable
+    pub async fn readiness(&self) -> Readiness {
+        let proxy = match self.proxy_socket_path() {
+            Some(path) => tokio::net::UnixStream::connect(path).await.into(),
+            None => tokio::net::TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], self.proxy_port))).await.into(),
+        };
+
+        let homeserver = match self.build_service_client().build().await {
+            Ok(client) => match tokio::time::timeout(Duration::from_secs(10), client.whoami()).await {
+                Ok(result) => result.into(),
+                Err(_) => HealthCheck::failed("Timed out waiting for whoami"),
+            },
+            Err(e) => HealthCheck::failed(e.to_string()),
+        };
+
+        let state = self.state.size_on_disk().into();
+
+        ReadinessChecks { proxy, homeserver, state }.into()
+    }
+
+    /// Path of the proxy's Unix socket, if using [ProxyTransport::Unix]
+    pub(crate) fn proxy_socket_path(&self) -> Option<PathBuf> {
+        self.proxy_socket.as_ref().map(|dir| dir.path().join("proxy.sock"))
+    }
+
     pub(crate) fn state_user_records(&self) -> crate::Result<crate::types::State<UserRecord>> {
         self.state::<UserRecord>("internal/user_records")
     }
 
+    pub(crate) fn state_media_cache(&self) -> crate::Result<crate::types::State<MediaCacheEntry>> {
+        self.state::<MediaCacheEntry>("internal/media_cache")
+    }
+
+    /// Maps media source URLs to content hashes in [Appservice::state_media_cache]
+    pub(crate) fn state_media_sources(&self) -> crate::Result<crate::types::State<String>> {
+        self.state::<String>("internal/media_sources")
+    }
+
+    /// Acknowledged inbound transaction IDs, with when they were acknowledged (in ms since the epoch)
+    pub(crate) fn state_transactions(&self) -> crate::Result<crate::types::State<i64>> {
+        self.state::<i64>("internal/transactions")
+    }
+
+    pub(crate) fn state_audit_log(&self) -> crate::Result<crate::types::State<AuditRecord>> {
+        self.state::<AuditRecord>("internal/audit_log")
+    }
+
+    /// Appends a record to the audit log and drops records past the retention period
+    pub(crate) fn record_audit(&self, record: AuditRecord) -> crate::Result<()> {
+        let Some(policy) = self.config().audit_log() else {
+            return Ok(());
+        };
+        let log = self.state_audit_log()?;
+        let _ = log.insert(audit_key(record.timestamp, self.state.generate_id()?), record)?;
+
+        if policy.retention_secs > 0 {
+            let cutoff = chrono::Utc::now() - chrono::Duration::seconds(policy.retention_secs as i64);
+            for key in log.range("", audit_key(cutoff, 0)).map(|(key, _)| key).collect::<Vec<_>>() {
+                let _ = log.remove(&key)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Queries the audit log of writes performed through the internal proxy, oldest first. Empty unless [Config::audit_log](crate::Config::audit_log) is set.
+    pub fn audit_log(&self, query: AuditQuery) -> crate::Result<Vec<AuditRecord>> {
+        let start = query.since.map(|since| audit_key(since, 0)).unwrap_or_default();
+        // '~' sorts after every digit, so this covers all keys
+        let end = query.until.map(|until| audit_key(until, 0)).unwrap_or_else(|| String::from("~"));
+
+        Ok(self
+            .state_audit_log()?
+            .range(start, end)
+            .map(|(_, record)| record)
+            .filter(|record| query.matches(record))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect())
+    }
+
+    /// Evicts expired upload cache entries, then (when over [MediaPolicy::cache_max_entries](crate::types::MediaPolicy::cache_max_entries))
+    /// the least recently used ones down to 90% of the limit, so uploads at the limit don't each rescan the cache.
+    /// Returns the number of evicted entries.
+    pub fn evict_media_cache(&self) -> crate::Result<usize> {
+        let policy = self.config().media();
+        let cache = self.state_media_cache()?;
+        let mut evicted = 0;
+
+        let mut entries = Vec::new();
+        for (hash, entry) in cache.entries() {
+            if entry.is_expired(policy.cache_max_age_secs) {
+                let _ = cache.remove(&hash)?;
+                evicted += 1;
+            } else {
+                entries.push((hash, entry.last_used));
+            }
+        }
+        if policy.cache_max_entries > 0 && entries.len() > policy.cache_max_entries {
+            entries.sort_by_key(|(_, last_used)| *last_used);
+            let low_water = policy.cache_max_entries - policy.cache_max_entries / 10;
+            let excess = entries.len() - low_water;
+            for (hash, _) in entries.drain(..excess) {
+                let _ = cache.remove(&hash)?;
+                evicted += 1;
+            }
+        }
+        *self.media_cache_len.lock() = Some(entries.len());
+
+        // Sources pointing at evicted content are stale
+        let sources = self.state_media_sources()?;
+        for (source, hash) in sources.entries() {
+            if cache.get(&hash)?.is_none() {
+                let _ = sources.remove(&source)?;
+            }
+        }
+
+        tracing::debug!(evicted, "Evicted media cache entries");
+        Ok(evicted)
+    }
+
+    /// Counts a new upload cache entry, evicting once the cache grows over its limit
+    pub(crate) fn media_cache_inserted(&self) -> crate::Result<()> {
+        let max_entries = self.config().media().cache_max_entries;
+        if max_entries == 0 {
+            return Ok(());
+        }
+
+        let over_limit = {
+            let mut len = self.media_cache_len.lock();
+            // Entries dropped elsewhere are only noticed by the next eviction, which merely makes it come early
+            let count = match *len {
+                Some(count) => count + 1,
+                None => self.state_media_cache()?.len(),
+            };
+            *len = Some(count);
+            count > max_entries
+        };
+        if over_limit {
+            let _ = self.evict_media_cache()?;
+        }
+        Ok(())
+    }
+
     pub(crate) fn store_client(&self, client: VirtualClient) -> () {
         let mut clients = self.clients.write();
         let _ = clients.insert(client.localpart(), client);
@@ -131,43 +396,114 @@ impl Appservice {
         clients.get(&localpart).and_then(|v| Some(v.clone()))
     }
 
-    pub(crate) fn add_proxy_directive(&self, target: ProxyDirectiveTarget, directive: ProxyDirective) -> () {
-        let mut directives = self.proxy_directives.write();
-        let _ = directives.insert(target, directive);
+    pub(crate) fn client_count(&self) -> usize {
+        self.clients.read().len()
     }
 
-    pub(crate) fn get_proxy_directive(&self, target: ProxyDirectiveTarget) -> Option<ProxyDirective> {
-        let mut directives = self.proxy_directives.write();
-        directives.remove(&target)
+    pub(crate) fn state_sizes(&self) -> Vec<(String, usize)> {
+        self.state
+            .tree_names()
+            .into_iter()
+            .filter_map(|name| {
+                self.state
+                    .open_tree(&name)
+                    .ok()
+                    .map(|tree| (String::from_utf8_lossy(&name).to_string(), tree.len()))
+            })
+            .collect()
+    }
+
+    /// Queues a one-shot directive for the next request matching `target`
+    pub fn add_proxy_directive(&self, target: ProxyDirectiveTarget, directive: ProxyDirective) -> () {
+        self.add_proxy_directives(target, [directive]);
+    }
+
+    /// Queues one-shot directives for a single upcoming request matching `target`. Each call queues a separate batch,
+    /// consumed in order by one request each. Batches whose request doesn't arrive within a minute are dropped.
+    pub fn add_proxy_directives(&self, target: ProxyDirectiveTarget, directives: impl IntoIterator<Item = ProxyDirective>) -> () {
+        let now = Instant::now();
+        let mut queued = self.proxy_directives.write();
+        queued.retain(|_, batches| {
+            batches.retain(|(expires, _)| *expires > now);
+            !batches.is_empty()
+        });
+        queued.entry(target).or_default().push_back((now + PROXY_DIRECTIVE_TTL, directives.into_iter().collect()));
+    }
+
+    /// Removes & returns the oldest unexpired batch of directives queued for `target`
+    pub(crate) fn take_proxy_directives(&self, target: &ProxyDirectiveTarget) -> Vec<ProxyDirective> {
+        let now = Instant::now();
+        let mut queued = self.proxy_directives.write();
+        let Some(batches) = queued.get_mut(target) else {
+            return Vec::new();
+        };
+
+        let mut directives = Vec::new();
+        while let Some((expires, batch)) = batches.pop_front() {
+            if expires > now {
+                directives = batch;
+                break;
+            }
+        }
+        if batches.is_empty() {
+            let _ = queued.remove(target);
+        }
+        directives
     }
 }
 
 impl Appservice {
+    /// Builds a matrix client that talks to the homeserver through the internal proxy, using the configured [ProxyTransport].
+    /// Also returns the underlying HTTP client, for requests the matrix client can't stream.
+    async fn configure_proxied_client(
+        &self,
+        matrix_client: Option<matrix_sdk::ClientBuilder>,
+        http_client: Option<reqwest::ClientBuilder>,
+        headers: reqwest::header::HeaderMap
+    ) -> crate::Result<(matrix_sdk::Client, reqwest::Client)> {
+        let matrix_client = matrix_client.unwrap_or(matrix_sdk::Client::builder());
+        let http_client = http_client
+            .unwrap_or(reqwest::Client::builder())
+            .default_headers(headers)
+            .user_agent(self.config().user_agent())
+            // External proxies (Config::proxy or the environment) only apply after the internal proxy
+            .no_proxy();
+
+        // An explicit port would bypass the proxy's resolver, the proxy restores the homeserver's port (and scheme)
+        let mut homeserver = self.config().homeserver_url()?;
+        let _ = homeserver.set_port(None);
+        let http_client = match self.proxy_socket_path() {
+            Some(path) => {
+                // Plain HTTP only exists on the socket
+                let _ = homeserver.set_scheme("http");
+                http_client.unix_socket(path).build()?
+            }
+            None => {
+                // The loopback proxy only speaks TLS, whatever the homeserver's scheme
+                let _ = homeserver.set_scheme("https");
+                http_client
+                    .add_root_certificate(Certificate::from_pem(self.certificate.as_bytes()).unwrap())
+                    .dns_resolver(Arc::new(crate::types::proxy::ProxyResolver::new(self.proxy_port)))
+                    .danger_accept_invalid_certs(true)
+                    .danger_accept_invalid_hostnames(true)
+                    .build()?
+            }
+        };
+
+        let client = matrix_client.http_client(http_client.clone()).homeserver_url(homeserver).build().await?;
+        Ok((client, http_client))
+    }
+
     pub(crate) async fn configure_service_client(
         &self,
         matrix_client: Option<matrix_sdk::ClientBuilder>,
         http_client: Option<reqwest::ClientBuilder>
-    ) -> crate::Result<matrix_sdk::Client> {
+    ) -> crate::Result<(matrix_sdk::Client, reqwest::Client)> {
         let mut headers = reqwest::header::HeaderMap::new();
         let _ = headers.insert("x-proxy-role", reqwest::header::HeaderValue::from_str("SERVICE").unwrap());
-        let _ = headers.insert("x-proxy-token", reqwest::header::HeaderValue::from_str(self.proxy_token().as_str()).unwrap());
-        println!("INNER_CONF");
-        let client = matrix_client
-            .unwrap_or(matrix_sdk::Client::builder())
-            .http_client(
-                http_client.unwrap_or(reqwest::Client::builder())
-                .add_root_certificate(Certificate::from_pem(self.certificate.as_bytes()).unwrap())
-                .default_headers(headers)
-                .dns_resolver(Arc::new(crate::types::proxy::ProxyResolver::new(self.proxy_port)))
-                .user_agent(self.config().user_agent())
-                .danger_accept_invalid_certs(true)
-                .danger_accept_invalid_hostnames(true)
-                .build()?
-            )
-            .server_name(&matrix_sdk::ruma::ServerName::parse(self.config().server_name()).unwrap())
-            .build().await?;
-
-        Ok(client)
+        let _ = headers.insert("x-proxy-token", reqwest::header::HeaderValue::from_str(self.proxy_token().expose()).unwrap());
+        tracing::debug!("Configuring service client");
+        self.configure_proxied_client(matrix_client, http_client, headers).await
     }
 
     pub(crate) async fn configure_bot_client(
@@ -175,35 +511,94 @@ impl Appservice {
         localpart: impl AsRef<str>,
         matrix_client: Option<matrix_sdk::ClientBuilder>,
         http_client: Option<reqwest::ClientBuilder>
-    ) -> crate::Result<matrix_sdk::Client> {
+    ) -> crate::Result<(matrix_sdk::Client, reqwest::Client)> {
         let localpart = localpart.as_ref().to_string();
+        tracing::debug!(localpart = %localpart, "Configuring bot client");
         let state = self.state_user_records()?;
         if let Some(user) = state.get(localpart.clone())? {
             let mut headers = reqwest::header::HeaderMap::new();
             let _ = headers.insert("x-proxy-role", reqwest::header::HeaderValue::from_str("BOT").unwrap());
-            let _ = headers.insert("x-proxy-token", reqwest::header::HeaderValue::from_str(self.proxy_token().as_str()).unwrap());
-            let _ = headers.insert("x-proxy-bot-token", reqwest::header::HeaderValue::from_str(user.token().as_str()).unwrap());
+            let _ = headers.insert("x-proxy-token", reqwest::header::HeaderValue::from_str(self.proxy_token().expose()).unwrap());
+            let _ = headers.insert("x-proxy-bot-token", reqwest::header::HeaderValue::from_str(user.token().expose()).unwrap());
             let _ = headers.insert("x-proxy-bot-user", reqwest::header::HeaderValue::from_str(&localpart).unwrap());
 
-            Ok(matrix_client
-                .unwrap_or(matrix_sdk::Client::builder())
-                .http_client(
-                    http_client.unwrap_or(reqwest::Client::builder())
-                    .add_root_certificate(Certificate::from_pem(self.certificate.as_bytes()).unwrap())
-                    .default_headers(headers)
-                    .dns_resolver(Arc::new(crate::types::proxy::ProxyResolver::new(self.proxy_port)))
-                    .user_agent(self.config().user_agent())
-                    .danger_accept_invalid_certs(true)
-                    .danger_accept_invalid_hostnames(true)
-                    .build()?
-                )
-                .server_name(&matrix_sdk::ruma::ServerName::parse(self.config().server_name()).unwrap())
-                .build().await?)
+            self.configure_proxied_client(matrix_client, http_client, headers).await
         } else {
             Err(crate::Error::UnregisteredUser(localpart))
         }
     }
 
+    /// Registers a ghost user with `m.login.application_service` (an existing account counts as registered) and persists its record,
+    /// so [Appservice::build_bot_client] can be used for it. Users that were registered before keep their record.
+    #[tracing::instrument(skip_all, fields(localpart = %localpart.as_ref()))]
+    pub async fn register_user(&self, localpart: impl AsRef<str>) -> crate::Result<UserRecord> {
+        let localpart = localpart.as_ref().to_string();
+        let server_name = self.config().server_name();
+        let user_id = matrix_sdk::ruma::UserId::parse_with_server_name(
+            localpart.as_str(),
+            &matrix_sdk::ruma::ServerName::parse(&server_name)?
+        )?;
+        if !self.config().is_user_in_namespace(&user_id) {
+            return Err(crate::Error::NotInNamespace(user_id.to_string()));
+        }
+
+        // Concurrent registrations of the same user wait for the first one, then find its record
+        let lock = self.registrations.lock().entry(localpart.clone()).or_default().clone();
+        let result = {
+            let _guard = lock.lock().await;
+            self.register_user_locked(&localpart, &server_name).await
+        };
+
+        let mut registrations = self.registrations.lock();
+        if Arc::strong_count(&lock) <= 2 {
+            let _ = registrations.remove(&localpart);
+        }
+        result
+    }
+
+    async fn register_user_locked(&self, localpart: &str, server_name: &str) -> crate::Result<UserRecord> {
+        let records = self.state_user_records()?;
+        if let Some(record) = records.get(localpart)? {
+            tracing::trace!("User already registered");
+            return Ok(record);
+        }
+
+        let service_client = self.build_service_client().build().await?;
+        let request = matrix_sdk::ruma::assign!(matrix_sdk::ruma::api::client::account::register::v3::Request::new(), {
+            username: Some(localpart.to_string()),
+            login_type: Some(matrix_sdk::ruma::api::client::account::register::LoginType::ApplicationService),
+            inhibit_login: true,
+        });
+        match service_client.send_traced(request).await {
+            Ok(_) => tracing::debug!("Registered user"),
+            Err(e) if matches!(e.client_api_error_kind(), Some(matrix_sdk::ruma::api::client::error::ErrorKind::UserInUse)) => {
+                tracing::debug!("User already exists on the homeserver");
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        // The homeserver may have set a default profile (usually the localpart as display name), which set_profile compares against
+        let mut record = UserRecord::new_with_id(localpart, server_name);
+        let profile = match service_client.send_traced(matrix_sdk::ruma::api::client::profile::get_profile::v3::Request::new(record.user_id())).await {
+            Ok(response) => {
+                let field = |name: &str| response.get(name).and_then(|v| v.as_str()).map(|v| v.to_string());
+                Profile::new(field("displayname"), field("avatar_url"))
+            }
+            Err(e) if matches!(e.client_api_error_kind(), Some(matrix_sdk::ruma::api::client::error::ErrorKind::NotFound)) => Profile::default(),
+            Err(e) => return Err(e.into()),
+        };
+        record.set_profile(profile);
+        if self.config().device_masquerading() {
+            // Bots act as this device, which has to exist first (MSC4190)
+            tracing::debug!(device_id = %record.device_id(), "Creating device");
+            let path = format!("/_matrix/client/v3/devices/{}", record.device_id());
+            service_client.add_proxy_directive(&path, ProxyDirective::UserId(record.get_user_id()));
+            service_client.send_traced(matrix_sdk::ruma::api::client::device::update_device::v3::Request::new(record.device_id())).await?;
+        }
+        let _ = records.insert(localpart, record.clone())?;
+        Ok(record)
+    }
+
     /// Creates a builder for a service (non-bot, using the sender_localpart) client
     pub fn build_service_client(&self) -> VirtualClientBuilder {
         VirtualClient::builder(self.clone(), self.config().sender_localpart())
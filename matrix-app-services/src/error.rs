@@ -19,7 +19,7 @@ pub enum Error {
 
     /// Error in the matrix-sdk crate
     #[error("Internal matrix SDK error: {0:?}")]
-    MatrixSdk(#[from] matrix_sdk::Error),
+    MatrixSdk(matrix_sdk::Error),
 
     /// Sled persistence error
     #[error("Internal sled persistence error: {0:?}")]
@@ -29,17 +29,49 @@ pub enum Error {
     #[error(transparent)]
     Io(#[from] io::Error),
 
+    /// The internal proxy couldn't bind its listener
+    #[error("Unable to bind the internal proxy: {0}")]
+    ProxyBind(#[source] io::Error),
+
     /// Reqwest error
     #[error("Encountered an issue in reqwest: {0:?}")]
     Reqwest(#[from] reqwest::Error),
 
+    /// The internal proxy refused to forward a request to a host that isn't the homeserver or explicitly allowed
+    #[error("Destination not allowed: {0}")]
+    DestinationNotAllowed(String),
+
+    /// Neither a homeserver base URL nor a server name to discover it from was configured, or discovery found none
+    #[error("No homeserver base URL configured or discovered")]
+    MissingHomeserver,
+
+    /// The homeserver is considered down by the circuit breaker, see [Appservice::status](crate::Appservice::status)
+    #[error("Homeserver unavailable")]
+    HomeserverUnavailable,
+
+    /// Media exceeded the configured size limit (in bytes)
+    #[error("Media exceeds the size limit of {0} bytes")]
+    MediaTooLarge(u64),
+
+    /// The user ID isn't covered by any of the appservice's user namespaces
+    #[error("User not in any appservice namespace: {0}")]
+    NotInNamespace(String),
+
+    /// The bot isn't joined to the room
+    #[error("Not joined to room: {0}")]
+    NotJoined(String),
+
     /// The requested user has not yet been registered/set up
     #[error("Unregistered user: {0}")]
     UnregisteredUser(String),
 
     /// YAML error
     #[error("YAML error: {0:?}")]
-    Yaml(#[from] serde_norway::Error)
+    Yaml(#[from] serde_norway::Error),
+
+    /// JSON error
+    #[error("JSON error: {0:?}")]
+    Json(#[from] serde_json::Error)
 }
 
 #[allow(missing_docs)]
@@ -49,6 +81,15 @@ impl Error {
     }
 }
 
+impl From<matrix_sdk::Error> for Error {
+    fn from(value: matrix_sdk::Error) -> Self {
+        if is_circuit_open(value.as_client_api_error()) {
+            return Self::HomeserverUnavailable;
+        }
+        Self::MatrixSdk(value)
+    }
+}
+
 impl From<rcgen::Error> for Error {
     fn from(value: rcgen::Error) -> Self {
         Self::Unknown(anyhow::Error::from(value))
@@ -67,8 +108,19 @@ impl From<ruma::IdParseError> for Error {
     }
 }
 
+/// Whether `error` is the proxy failing fast because the circuit breaker is open
+fn is_circuit_open(error: Option<&ruma::api::client::Error>) -> bool {
+    error.is_some_and(|e| {
+        e.status_code == axum::http::StatusCode::SERVICE_UNAVAILABLE
+            && matches!(&e.body, ruma::api::client::error::ErrorBody::Standard { message, .. } if message == crate::servers::breaker::UNAVAILABLE_MESSAGE)
+    })
+}
+
 impl From<matrix_sdk::HttpError> for Error {
     fn from(value: matrix_sdk::HttpError) -> Self {
+        if is_circuit_open(value.as_client_api_error()) {
+            return Self::HomeserverUnavailable;
+        }
         Self::Unknown(anyhow::Error::from(value))
     }
 }
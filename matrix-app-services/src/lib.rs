@@ -4,7 +4,7 @@
 
 ///
 pub mod types;
-pub use types::{Config, Namespace};
+pub use types::{Config, MediaDownload, Namespace, ProxyTransport};
 
 ///
 mod error;
@@ -17,11 +17,15 @@ pub use client::Appservice;
 
 ///
 pub mod virtual_client;
-pub use virtual_client::{VirtualClient, VirtualClientKind};
+pub use virtual_client::{TimestampedClient, VirtualClient, VirtualClientKind};
 
 ///
 pub mod servers;
 
+///
+pub mod metrics;
+pub use metrics::Metrics;
+
 ///
 pub(crate) mod util;
 pub(crate) use util::*;
@@ -0,0 +1,171 @@
+use prometheus::{
+    exponential_buckets,
+    Histogram,
+    HistogramOpts,
+    HistogramVec,
+    IntCounterVec,
+    IntGauge,
+    IntGaugeVec,
+    Opts,
+    Registry,
+    TextEncoder,
+};
+
+use crate::client::Appservice;
+
+/// Path segments after which the next N segments are opaque values rather than part of the endpoint
+const OPAQUE_SEGMENTS: &[(&str, usize)] = &[
+    ("send", 2),
+    ("state", 2),
+    ("sendToDevice", 2),
+    ("redact", 2),
+    ("download", 3),
+    ("thumbnail", 2),
+    ("account_data", 1),
+    ("tags", 1),
+    ("receipt", 2),
+    ("devices", 1),
+    ("transactions", 1),
+    ("relations", 3),
+    ("filter", 1),
+];
+
+/// Prometheus metrics collected by an [Appservice]
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    registry: Registry,
+    pub(crate) transactions: IntCounterVec,
+    pub(crate) transaction_events: Histogram,
+    pub(crate) transaction_duration: Histogram,
+    pub(crate) proxy_requests: IntCounterVec,
+    pub(crate) proxy_latency: HistogramVec,
+    pub(crate) proxy_queued: IntGauge,
+    pub(crate) proxy_queued_users: IntGauge,
+    pub(crate) proxy_queue_wait: Histogram,
+    pub(crate) proxy_cache: IntCounterVec,
+    pub(crate) homeserver_available: IntGauge,
+    virtual_clients: IntGauge,
+    state_entries: IntGaugeVec,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        let registry = Registry::new();
+
+        let transactions = IntCounterVec::new(
+            Opts::new("appservice_transactions_total", "Inbound transactions from the homeserver"),
+            &["result"]
+        ).unwrap();
+        let transaction_events = Histogram::with_opts(
+            HistogramOpts::new("appservice_transaction_events", "Events per inbound transaction")
+                .buckets(vec![0.0, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 250.0, 500.0, 1000.0])
+        ).unwrap();
+        let transaction_duration = Histogram::with_opts(
+            HistogramOpts::new("appservice_transaction_duration_seconds", "Time spent handling inbound transactions")
+                .buckets(exponential_buckets(0.0005, 2.0, 16).unwrap())
+        ).unwrap();
+        let proxy_requests = IntCounterVec::new(
+            Opts::new("appservice_proxy_requests_total", "Requests passed through the internal proxy"),
+            &["method", "endpoint", "status", "role"]
+        ).unwrap();
+        let proxy_latency = HistogramVec::new(
+            HistogramOpts::new("appservice_proxy_upstream_latency_seconds", "Time until the homeserver answered a proxied request")
+                .buckets(exponential_buckets(0.001, 2.0, 16).unwrap()),
+            &["method", "endpoint", "role"]
+        ).unwrap();
+        let proxy_queued = IntGauge::new("appservice_proxy_queued_requests", "Proxied requests waiting for a scheduling slot").unwrap();
+        let proxy_queued_users = IntGauge::new("appservice_proxy_queued_users", "Users with proxied requests waiting for a scheduling slot").unwrap();
+        let proxy_queue_wait = Histogram::with_opts(
+            HistogramOpts::new("appservice_proxy_queue_wait_seconds", "Time proxied requests spent waiting for a scheduling slot")
+                .buckets(exponential_buckets(0.001, 2.0, 16).unwrap())
+        ).unwrap();
+        let proxy_cache = IntCounterVec::new(
+            Opts::new("appservice_proxy_cache_lookups_total", "Response cache lookups in the internal proxy"),
+            &["result"]
+        ).unwrap();
+        let homeserver_available = IntGauge::new("appservice_homeserver_available", "Whether the proxy's circuit breaker considers the homeserver reachable").unwrap();
+        homeserver_available.set(1);
+        let virtual_clients = IntGauge::new("appservice_virtual_clients", "Cached virtual clients").unwrap();
+        let state_entries = IntGaugeVec::new(
+            Opts::new("appservice_state_entries", "Entries per state tree"),
+            &["tree"]
+        ).unwrap();
+
+        registry.register(Box::new(transactions.clone())).unwrap();
+        registry.register(Box::new(transaction_events.clone())).unwrap();
+        registry.register(Box::new(transaction_duration.clone())).unwrap();
+        registry.register(Box::new(proxy_requests.clone())).unwrap();
+        registry.register(Box::new(proxy_latency.clone())).unwrap();
+        registry.register(Box::new(proxy_queued.clone())).unwrap();
+        registry.register(Box::new(proxy_queued_users.clone())).unwrap();
+        registry.register(Box::new(proxy_queue_wait.clone())).unwrap();
+        registry.register(Box::new(proxy_cache.clone())).unwrap();
+        registry.register(Box::new(homeserver_available.clone())).unwrap();
+        registry.register(Box::new(virtual_clients.clone())).unwrap();
+        registry.register(Box::new(state_entries.clone())).unwrap();
+
+        Self {
+            registry,
+            transactions,
+            transaction_events,
+            transaction_duration,
+            proxy_requests,
+            proxy_latency,
+            proxy_queued,
+            proxy_queued_users,
+            proxy_queue_wait,
+            proxy_cache,
+            homeserver_available,
+            virtual_clients,
+            state_entries,
+        }
+    }
+
+    /// Gets the underlying registry, e.g. to register additional collectors
+    pub fn registry(&self) -> Registry {
+        self.registry.clone()
+    }
+
+    /// Refreshes point-in-time gauges and renders all metrics in the Prometheus text format
+    pub(crate) fn render(&self, service: &Appservice) -> crate::Result<String> {
+        self.virtual_clients.set(service.client_count() as i64);
+        for (tree, entries) in service.state_sizes() {
+            self.state_entries.with_label_values(&[tree.as_str()]).set(entries as i64);
+        }
+
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .or_else(|e| Err(crate::Error::Unknown(anyhow::Error::from(e))))
+    }
+}
+
+/// Reduces a request path to a low-cardinality endpoint template (`/rooms/{roomId}/send/{param}/{param}`)
+pub(crate) fn endpoint_template(path: &str) -> String {
+    let mut opaque = 0usize;
+    let mut template = String::new();
+
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        let replacement = if opaque > 0 {
+            opaque -= 1;
+            "{param}"
+        } else if segment.starts_with('!') || segment.starts_with("%21") {
+            "{roomId}"
+        } else if segment.starts_with('@') || segment.starts_with("%40") {
+            "{userId}"
+        } else if segment.starts_with('#') || segment.starts_with("%23") {
+            "{roomAlias}"
+        } else if segment.starts_with('$') || segment.starts_with("%24") {
+            "{eventId}"
+        } else {
+            if let Some((_, count)) = OPAQUE_SEGMENTS.iter().find(|(name, _)| *name == segment) {
+                opaque = *count;
+            }
+            segment
+        };
+
+        template.push('/');
+        template.push_str(replacement);
+    }
+
+    if template.is_empty() { String::from("/") } else { template }
+}
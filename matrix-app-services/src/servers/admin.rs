@@ -0,0 +1,39 @@
+use axum::{ extract::State, http::{ header::CONTENT_TYPE, StatusCode }, response::IntoResponse, routing::get, Json, Router };
+
+use crate::client::Appservice;
+
+async fn handle_live() -> axum::response::Response {
+    Json(serde_json::json!({ "alive": true })).into_response()
+}
+
+async fn handle_ready(State(service): State<Appservice>) -> axum::response::Response {
+    let readiness = service.readiness().await;
+    let status = if readiness.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(readiness)).into_response()
+}
+
+async fn handle_metrics(State(service): State<Appservice>) -> axum::response::Response {
+    match service.metrics().render(&service) {
+        Ok(rendered) => ([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], rendered).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Routes for `/health`, `/live`, `/ready` & `/metrics`
+pub fn routes() -> Router<Appservice> {
+    Router::new()
+        .route("/health", get(handle_live))
+        .route("/live", get(handle_live))
+        .route("/ready", get(handle_ready))
+        .route("/metrics", get(handle_metrics))
+}
+
+pub async fn serve_admin(service: Appservice) -> crate::Result<()> {
+    let Some(address) = service.config().admin_address() else {
+        return Ok(());
+    };
+
+    let handler = routes().with_state(service.clone()).into_make_service();
+    axum_server::bind(address).serve(handler).await?;
+    Ok(())
+}
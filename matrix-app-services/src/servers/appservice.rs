@@ -1,16 +1,246 @@
-use axum::{RequestExt, Router};
-use matrix_sdk::stream::StreamExt;
+use std::{ fmt::Debug, os::unix::fs::FileTypeExt, sync::Arc, time::Instant };
 
-use crate::{ client::Appservice, Config };
+use axum::{
+    extract::{ Path, State },
+    http::{ header::AUTHORIZATION, StatusCode },
+    response::IntoResponse,
+    routing::put,
+    Json,
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use futures_util::future::BoxFuture;
+use matrix_sdk::ruma::api::{ appservice::event::push_events, IncomingRequest };
 
-async fn 
+use crate::{ client::Appservice, matrix_error };
 
-pub async fn serve_appservice(service: Appservice) -> crate::Result<()> {
-    let handler = Router::new()
+/// How many acknowledged transaction IDs are remembered to skip retries, the oldest are forgotten first
+const REMEMBERED_TRANSACTIONS: usize = 10_000;
+
+/// Handles the events of an inbound transaction, see [Appservice::on_transaction]
+#[derive(Clone)]
+pub(crate) struct TransactionHandler(pub Arc<dyn Fn(Appservice, push_events::v1::Request) -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync>);
+
+impl Debug for TransactionHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("TransactionHandler").finish()
+    }
+}
+
+/// Checks the homeserver token, either from the `Authorization` header or the legacy `access_token` query parameter
+fn verify_homeserver(service: &Appservice, request: &axum::extract::Request) -> Result<(), (StatusCode, &'static str, &'static str)> {
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.to_string())
+        .or_else(|| {
+            url::form_urlencoded
+                ::parse(request.uri().query().unwrap_or_default().as_bytes())
+                .find(|(key, _)| key == "access_token")
+                .map(|(_, value)| value.to_string())
+        });
+
+    match token {
+        Some(token) if service.config().homeserver_token().matches(&token) => Ok(()),
+        Some(_) => Err((StatusCode::FORBIDDEN, "M_FORBIDDEN", "Invalid homeserver token")),
+        None => Err((StatusCode::UNAUTHORIZED, "M_UNAUTHORIZED", "Missing homeserver token")),
+    }
+}
+
+/// Remembers an acknowledged transaction, forgetting the oldest ones past [REMEMBERED_TRANSACTIONS]
+fn remember_transaction(service: &Appservice, txn_id: &str) -> crate::Result<()> {
+    let transactions = service.state_transactions()?;
+    let _ = transactions.insert(txn_id, chrono::Utc::now().timestamp_millis())?;
+    if transactions.len() > REMEMBERED_TRANSACTIONS {
+        let mut remembered = transactions.entries().collect::<Vec<_>>();
+        remembered.sort_by_key(|(_, acknowledged)| *acknowledged);
+        // Down to 90%, so this doesn't scan the whole tree on every transaction
+        for (txn_id, _) in remembered.iter().take(remembered.len() - REMEMBERED_TRANSACTIONS * 9 / 10) {
+            let _ = transactions.remove(txn_id)?;
+        }
+    }
+    Ok(())
+}
+
+/// Hands pushed events to the [TransactionHandler], only acknowledging the transaction once it succeeded.
+/// The handler runs in this span, so requests it makes with [crate::VirtualClient::send_traced] or the media helpers carry its ID to the proxy.
+#[tracing::instrument(name = "transaction", skip_all, fields(txn_id = %txn_id, events))]
+async fn handle_transaction(
+    State(service): State<Appservice>,
+    Path(txn_id): Path<String>,
+    request: axum::extract::Request
+) -> axum::response::Response {
+    if let Err((status, errcode, error)) = verify_homeserver(&service, &request) {
+        tracing::warn!(%status, "Rejected transaction from unauthenticated homeserver");
+        return matrix_error(status, errcode, error);
+    }
+
+    let metrics = service.metrics();
+    let started = Instant::now();
+    match service.state_transactions().and_then(|transactions| transactions.get(&txn_id)) {
+        Ok(Some(_)) => {
+            tracing::debug!("Transaction already handled");
+            metrics.transactions.with_label_values(&["duplicate"]).inc();
+            return Json(serde_json::json!({})).into_response();
+        }
+        Ok(None) => (),
+        Err(e) => tracing::warn!(error = %e, "Failed to look up handled transactions"),
+    }
+
+    let (parts, body) = request.into_parts();
+    let parsed = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => push_events::v1::Request::try_from_http_request(
+            axum::http::Request::from_parts(parts, bytes),
+            std::slice::from_ref(&txn_id)
+        ).or_else(|e| Err(e.to_string())),
+        Err(e) => Err(e.to_string()),
+    };
+    let transaction = match parsed {
+        Ok(transaction) => transaction,
+        Err(e) => {
+            tracing::warn!(error = %e, "Failed to parse transaction");
+            metrics.transactions.with_label_values(&["failure"]).inc();
+            return matrix_error(StatusCode::BAD_REQUEST, "M_BAD_JSON", e);
+        }
+    };
+    tracing::Span::current().record("events", transaction.events.len());
+    for event in &transaction.events {
+        tracing::debug!(
+            event_id = ?event.get_field::<String>("event_id").ok().flatten(),
+            room_id = ?event.get_field::<String>("room_id").ok().flatten(),
+            sender = ?event.get_field::<String>("sender").ok().flatten(),
+            event_type = ?event.get_field::<String>("type").ok().flatten(),
+            "Received event"
+        );
+    }
+    metrics.transaction_events.observe(transaction.events.len() as f64);
+
+    let handled = match service.transaction_handler() {
+        Some(TransactionHandler(handler)) => handler(service.clone(), transaction).await,
+        None => {
+            tracing::debug!("No transaction handler, ignoring events");
+            Ok(())
+        }
+    };
+    let result = handled.and_then(|_| remember_transaction(&service, &txn_id).or_else(|e| Err(anyhow::Error::from(e))));
+    metrics.transaction_duration.observe(started.elapsed().as_secs_f64());
+
+    match result {
+        Ok(()) => {
+            metrics.transactions.with_label_values(&["success"]).inc();
+            Json(serde_json::json!({})).into_response()
+        }
+        Err(e) => {
+            // Not acknowledged, so the homeserver retries the transaction
+            tracing::warn!(error = ?e, "Failed to handle transaction");
+            metrics.transactions.with_label_values(&["failure"]).inc();
+            matrix_error(StatusCode::INTERNAL_SERVER_ERROR, "M_UNKNOWN", "Failed to handle transaction")
+        }
+    }
+}
+
+async fn handle_service() -> axum::response::Response {
+    matrix_error(StatusCode::NOT_FOUND, "M_UNRECOGNIZED", "Unrecognized request")
+}
+
+fn appservice_router(service: Appservice) -> Router {
+    Router::new()
+        .route("/_matrix/app/v1/transactions/{txn_id}", put(handle_transaction))
         .fallback(handle_service)
-        .with_state(service.clone())
-        .into_make_service();
-    println!("Hosting appservice...");
-    axum_server::bind(service.config().local_address()).serve(handler).await.expect("Failed to host appservice");
+        .with_state(service)
+}
+
+pub async fn serve_appservice(service: Appservice) -> crate::Result<()> {
+    let config = service.config();
+    let handler = appservice_router(service.clone());
+    tracing::info!(
+        address = %config.local_address(),
+        socket = ?config.local_socket(),
+        tls = config.local_tls().is_some(),
+        "Hosting appservice"
+    );
+
+    if let Some(path) = config.local_socket() {
+        // Clean up a stale socket from a previous run, but never remove anything else
+        if let Ok(metadata) = std::fs::symlink_metadata(&path) && metadata.file_type().is_socket() {
+            std::fs::remove_file(&path)?;
+        }
+
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        axum::serve(listener, handler.into_make_service()).await?;
+    } else if let Some(tls) = config.local_tls() {
+        let tls_config = RustlsConfig::from_pem_file(tls.certificate, tls.key).await?;
+        axum_server::bind_rustls(config.local_address(), tls_config).serve(handler.into_make_service()).await?;
+    } else {
+        axum_server::bind(config.local_address()).serve(handler.into_make_service()).await?;
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{ atomic::{ AtomicUsize, Ordering }, Arc };
+
+    use axum::{ body::Body, http::{ Request, StatusCode } };
+    use tower::ServiceExt;
+
+    use super::appservice_router;
+    use crate::{ Appservice, Config, Namespace };
+
+    fn service() -> Appservice {
+        let config = Config::builder("test")
+            .homeserver("http://homeserver.test")
+            .sender_localpart("bot")
+            .appservice_token("as_token")
+            .homeserver_token("hs_token")
+            .namespace(Namespace::user("@bot_.*"))
+            .build();
+        Appservice::new(config).unwrap()
+    }
+
+    async fn push(service: &Appservice, txn_id: &str, token: Option<&str>) -> StatusCode {
+        let mut request = Request::put(format!("/_matrix/app/v1/transactions/{txn_id}"));
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {token}"));
+        }
+        let request = request.body(Body::from(r#"{"events":[{"type":"m.room.message","event_id":"$a"}]}"#)).unwrap();
+        appservice_router(service.clone()).oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn rejects_transactions_without_the_homeserver_token() {
+        let service = service();
+        assert_eq!(push(&service, "1", None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(push(&service, "1", Some("as_token")).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn acknowledges_transactions_once_handled() {
+        let service = service();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        service.on_transaction(move |_, transaction| {
+            let call = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                assert_eq!(transaction.events.len(), 1);
+                anyhow::ensure!(call > 0, "first attempt fails");
+                Ok(())
+            }
+        });
+
+        // A failed attempt isn't acknowledged, so its retry is handled, but a retry of an acknowledged transaction isn't
+        assert_eq!(push(&service, "1", Some("hs_token")).await, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(push(&service, "1", Some("hs_token")).await, StatusCode::OK);
+        assert_eq!(push(&service, "1", Some("hs_token")).await, StatusCode::OK);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let metrics = service.metrics();
+        assert_eq!(metrics.transactions.with_label_values(&["failure"]).get(), 1);
+        assert_eq!(metrics.transactions.with_label_values(&["success"]).get(), 1);
+        assert_eq!(metrics.transactions.with_label_values(&["duplicate"]).get(), 1);
+        assert_eq!(metrics.transaction_events.get_sample_count(), 2);
+    }
+}
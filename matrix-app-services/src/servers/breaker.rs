@@ -0,0 +1,91 @@
+use std::{ sync::Arc, time::Duration };
+
+use chrono::Utc;
+use parking_lot::Mutex;
+
+use crate::{ metrics::Metrics, types::{ CircuitBreakerPolicy, HomeserverStatus } };
+
+/// Message of the error the proxy answers with while the circuit is open, recognized by [crate::Error]'s conversions
+pub(crate) const UNAVAILABLE_MESSAGE: &str = "Homeserver unavailable, failing fast until it recovers";
+
+#[derive(Debug)]
+struct BreakerState {
+    status: HomeserverStatus,
+    failures: u32,
+    probing: bool,
+}
+
+/// Tracks upstream failures and fails requests fast once the homeserver appears down
+#[derive(Clone, Debug)]
+pub(crate) struct CircuitBreaker {
+    policy: CircuitBreakerPolicy,
+    state: Arc<Mutex<BreakerState>>,
+    metrics: Metrics,
+}
+
+impl CircuitBreaker {
+    pub fn new(policy: CircuitBreakerPolicy, metrics: Metrics) -> Self {
+        Self {
+            policy,
+            state: Arc::new(Mutex::new(BreakerState { status: HomeserverStatus::Available, failures: 0, probing: false })),
+            metrics,
+        }
+    }
+
+    pub fn status(&self) -> HomeserverStatus {
+        self.state.lock().status.clone()
+    }
+
+    pub fn is_open(&self) -> bool {
+        !matches!(self.state.lock().status, HomeserverStatus::Available)
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock();
+        if matches!(state.status, HomeserverStatus::Available) {
+            state.failures = 0;
+        }
+    }
+
+    /// Counts a failed upstream request, opening the circuit (and starting to probe `probe`) at the threshold
+    pub fn record_failure(&self, client: &reqwest::Client, probe: url::Url) {
+        let mut guard = self.state.lock();
+        let state = &mut *guard;
+        state.failures = state.failures.saturating_add(1);
+        match state.status {
+            HomeserverStatus::Unavailable { ref mut failures, .. } => *failures = state.failures,
+            HomeserverStatus::Available if state.failures >= self.policy.failure_threshold => {
+                tracing::warn!(failures = state.failures, "Homeserver appears to be down, opening circuit");
+                state.status = HomeserverStatus::Unavailable { since: Utc::now(), failures: state.failures };
+                self.metrics.homeserver_available.set(0);
+            }
+            HomeserverStatus::Available => (),
+        }
+
+        if !state.probing && !matches!(state.status, HomeserverStatus::Available) {
+            state.probing = true;
+            tokio::spawn(self.clone().probe(client.clone(), probe));
+        }
+    }
+
+    /// Polls `/versions` until the homeserver answers, then closes the circuit
+    async fn probe(self, client: reqwest::Client, url: url::Url) {
+        let interval = Duration::from_millis(self.policy.probe_interval_ms);
+        loop {
+            tokio::time::sleep(interval).await;
+            let result = client.get(url.clone()).timeout(interval.max(Duration::from_secs(1))).send().await;
+            match result {
+                Ok(response) if response.status().is_success() => break,
+                Ok(response) => tracing::debug!(status = %response.status(), "Homeserver probe failed"),
+                Err(e) => tracing::debug!(error = %e, "Homeserver probe failed"),
+            }
+        }
+
+        let mut state = self.state.lock();
+        tracing::info!("Homeserver is reachable again, closing circuit");
+        state.status = HomeserverStatus::Available;
+        state.failures = 0;
+        state.probing = false;
+        self.metrics.homeserver_available.set(1);
+    }
+}
@@ -0,0 +1,280 @@
+use std::{ collections::HashMap, sync::Arc, time::{ Duration, Instant } };
+
+use axum::http::{ header::CACHE_CONTROL, HeaderMap, StatusCode };
+use matrix_sdk::bytes::Bytes;
+use parking_lot::Mutex;
+
+use crate::{ metrics::Metrics, types::ResponseCachePolicy };
+
+/// A cached upstream response
+#[derive(Clone, Debug)]
+pub(crate) struct CachedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    expires: Instant,
+    resource: Option<String>,
+    membership: bool,
+}
+
+/// In-memory cache of GET responses passing through the proxy, keyed by masqueraded user & URL
+#[derive(Clone, Debug)]
+pub(crate) struct ResponseCache {
+    policy: ResponseCachePolicy,
+    entries: Arc<Mutex<HashMap<String, CachedResponse>>>,
+    metrics: Metrics,
+}
+
+/// Parsed `Cache-Control` directives relevant to the cache
+#[derive(Debug, Default)]
+struct CacheControl {
+    no_cache: bool,
+    no_store: bool,
+    max_age: Option<u64>,
+}
+
+impl CacheControl {
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut parsed = Self::default();
+        for directive in headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|v| v.trim().to_ascii_lowercase())
+        {
+            match directive.split_once('=') {
+                Some(("max-age", value)) => parsed.max_age = value.trim_matches('"').parse().ok(),
+                _ if directive == "no-cache" => parsed.no_cache = true,
+                _ if directive == "no-store" => parsed.no_store = true,
+                _ => (),
+            }
+        }
+
+        parsed
+    }
+}
+
+/// The room or user an API path is about (`rooms/!room:server`, `profile/@user:server`), used to invalidate related reads.
+/// Joining or knocking by room ID is about that room.
+pub(crate) fn resource_of(api_path: &str) -> Option<String> {
+    let segments = api_path
+        .split('/')
+        .map(|segment| percent_encoding::percent_decode_str(segment).decode_utf8_lossy().to_string())
+        .collect::<Vec<_>>();
+
+    segments
+        .windows(2)
+        .find_map(|pair| match pair[0].as_str() {
+            "rooms" | "profile" if !pair[1].is_empty() => Some(format!("{}/{}", pair[0], pair[1])),
+            "join" | "knock" if pair[1].starts_with('!') => Some(format!("rooms/{}", pair[1])),
+            _ => None,
+        })
+}
+
+/// Whether a read includes member events (`/members`, `/joined_members`, `/state`), which profile changes & joins by alias affect
+fn has_membership(api_path: &str) -> bool {
+    api_path.split('/').any(|segment| matches!(segment, "members" | "joined_members" | "state"))
+}
+
+/// Whether a write changes member events in rooms it doesn't name: profile changes propagate to every joined room,
+/// and joins by alias don't say which room they are about
+fn changes_membership(api_path: &str) -> bool {
+    let segments = api_path.split('/').collect::<Vec<_>>();
+    segments.windows(2).any(|pair| match pair[0] {
+        "profile" => !pair[1].is_empty(),
+        "join" | "knock" => !pair[1].is_empty() && !pair[1].starts_with('!') && !pair[1].starts_with("%21"),
+        _ => false,
+    })
+}
+
+impl ResponseCache {
+    pub fn new(policy: ResponseCachePolicy, metrics: Metrics) -> Self {
+        Self { policy, entries: Arc::new(Mutex::new(HashMap::new())), metrics }
+    }
+
+    /// Whether GET requests to `api_path` are cached at all
+    pub fn is_cacheable(&self, api_path: &str) -> bool {
+        let template = crate::metrics::endpoint_template(api_path);
+        self.policy.endpoints.contains(&template)
+    }
+
+    /// Looks up a fresh response, unless the request asks to bypass caches
+    pub fn get(&self, key: &str, request_headers: &HeaderMap) -> Option<CachedResponse> {
+        if CacheControl::parse(request_headers).no_cache {
+            self.metrics.proxy_cache.with_label_values(&["bypass"]).inc();
+            return None;
+        }
+
+        let mut entries = self.entries.lock();
+        let result = match entries.get(key) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.clone()),
+            Some(_) => {
+                let _ = entries.remove(key);
+                None
+            }
+            None => None,
+        };
+        self.metrics.proxy_cache.with_label_values(&[if result.is_some() { "hit" } else { "miss" }]).inc();
+        result
+    }
+
+    /// Whether a response of `size` bytes may be stored
+    pub fn fits(&self, size: Option<u64>) -> bool {
+        size.is_some_and(|size| size <= self.policy.max_body as u64)
+    }
+
+    /// Stores a successful response, honoring its `Cache-Control` directives
+    pub fn insert(&self, key: String, api_path: &str, status: StatusCode, headers: HeaderMap, body: Bytes) {
+        let control = CacheControl::parse(&headers);
+        if status != StatusCode::OK || control.no_store || control.no_cache {
+            return;
+        }
+        let ttl = match control.max_age {
+            Some(max_age) => max_age.min(self.policy.ttl_secs),
+            None => self.policy.ttl_secs,
+        };
+        if ttl == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+        if self.policy.max_entries > 0 && entries.len() >= self.policy.max_entries {
+            entries.retain(|_, entry| entry.expires > now);
+            // Still full, drop whatever expires first
+            if entries.len() >= self.policy.max_entries
+                && let Some(oldest) = entries.iter().min_by_key(|(_, entry)| entry.expires).map(|(key, _)| key.clone())
+            {
+                let _ = entries.remove(&oldest);
+            }
+        }
+        let _ = entries.insert(key, CachedResponse {
+            status,
+            headers,
+            body,
+            expires: now + Duration::from_secs(ttl),
+            resource: resource_of(api_path),
+            membership: has_membership(api_path),
+        });
+    }
+
+    /// Drops every cached read about the same room or user as a write to `api_path`, and member & state reads if it may change them
+    pub fn invalidate(&self, api_path: &str) {
+        let resource = resource_of(api_path);
+        let membership = changes_membership(api_path);
+        if resource.is_none() && !membership {
+            return;
+        }
+
+        let mut entries = self.entries.lock();
+        let before = entries.len();
+        entries.retain(|_, entry| !((resource.is_some() && entry.resource == resource) || (membership && entry.membership)));
+        let removed = before - entries.len();
+        if removed > 0 {
+            tracing::trace!(resource = ?resource, membership, removed, "Invalidated cached responses");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{ header::CACHE_CONTROL, HeaderMap, StatusCode };
+    use matrix_sdk::bytes::Bytes;
+
+    use super::{ changes_membership, has_membership, resource_of, ResponseCache };
+    use crate::{ metrics::Metrics, types::ResponseCachePolicy };
+
+    const ROOM: &str = "/_matrix/client/v3/rooms/!room:server";
+    const PROFILE: &str = "/_matrix/client/v3/profile/@user:server";
+
+    fn cache() -> ResponseCache {
+        ResponseCache::new(ResponseCachePolicy::default(), Metrics::new())
+    }
+
+    fn headers(cache_control: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, cache_control.parse().unwrap());
+        headers
+    }
+
+    fn store(cache: &ResponseCache, api_path: &str, response_headers: HeaderMap) {
+        cache.insert(String::from(api_path), api_path, StatusCode::OK, response_headers, Bytes::from_static(b"{}"));
+    }
+
+    fn cached(cache: &ResponseCache, api_path: &str) -> bool {
+        cache.entries.lock().contains_key(api_path)
+    }
+
+    #[test]
+    fn joins_by_room_id_are_about_that_room() {
+        assert_eq!(resource_of("/_matrix/client/v3/join/!room:server").as_deref(), Some("rooms/!room:server"));
+        assert_eq!(resource_of("/_matrix/client/v3/knock/%21room%3Aserver").as_deref(), Some("rooms/!room:server"));
+        assert_eq!(resource_of("/_matrix/client/v3/rooms/%21room%3Aserver/members").as_deref(), Some("rooms/!room:server"));
+        assert_eq!(resource_of("/_matrix/client/v3/join/#alias:server"), None);
+
+        assert!(!changes_membership("/_matrix/client/v3/join/!room:server"));
+        assert!(!changes_membership("/_matrix/client/v3/join/%21room%3Aserver"));
+        assert!(changes_membership("/_matrix/client/v3/join/%23alias%3Aserver"));
+        assert!(changes_membership(&format!("{PROFILE}/displayname")));
+        assert!(!changes_membership(&format!("{ROOM}/send/m.room.message/1")));
+    }
+
+    #[test]
+    fn member_reads_are_recognized() {
+        assert!(has_membership(&format!("{ROOM}/members")));
+        assert!(has_membership(&format!("{ROOM}/joined_members")));
+        assert!(has_membership(&format!("{ROOM}/state/m.room.member/@user:server")));
+        assert!(!has_membership(PROFILE));
+    }
+
+    #[test]
+    fn room_writes_drop_that_room_only() {
+        let cache = cache();
+        let other = "/_matrix/client/v3/rooms/!other:server/members";
+        store(&cache, &format!("{ROOM}/members"), HeaderMap::new());
+        store(&cache, other, HeaderMap::new());
+
+        cache.invalidate("/_matrix/client/v3/join/%21room%3Aserver");
+        assert!(!cached(&cache, &format!("{ROOM}/members")));
+        assert!(cached(&cache, other));
+    }
+
+    #[test]
+    fn profile_and_alias_join_writes_drop_member_reads() {
+        for write in [format!("{PROFILE}/displayname"), String::from("/_matrix/client/v3/join/%23alias%3Aserver")] {
+            let cache = cache();
+            for read in [format!("{ROOM}/members"), format!("{ROOM}/state"), String::from(PROFILE), String::from("/_matrix/client/v3/profile/@other:server")] {
+                store(&cache, &read, HeaderMap::new());
+            }
+
+            cache.invalidate(&write);
+            assert!(!cached(&cache, &format!("{ROOM}/members")), "{write}");
+            assert!(!cached(&cache, &format!("{ROOM}/state")), "{write}");
+            assert!(cached(&cache, "/_matrix/client/v3/profile/@other:server"), "{write}");
+            // The profile itself only for profile writes
+            assert_eq!(cached(&cache, PROFILE), !write.starts_with(PROFILE), "{write}");
+        }
+    }
+
+    #[test]
+    fn honors_cache_control() {
+        let cache = cache();
+        store(&cache, "max-age", headers("public, max-age=10"));
+        store(&cache, "expired", headers("max-age=0"));
+        store(&cache, "no-store", headers("no-store"));
+        store(&cache, "no-cache", headers("No-Cache"));
+        assert!(cached(&cache, "max-age"));
+        assert!(!cached(&cache, "expired"));
+        assert!(!cached(&cache, "no-store"));
+        assert!(!cached(&cache, "no-cache"));
+
+        // max-age only shortens the configured TTL
+        store(&cache, "long", headers("max-age=3600"));
+        let ttl = |key: &str| cache.entries.lock()[key].expires.duration_since(std::time::Instant::now()).as_secs();
+        assert!(ttl("max-age") <= 10 && ttl("long") <= ResponseCachePolicy::default().ttl_secs);
+
+        assert!(cache.get("max-age", &HeaderMap::new()).is_some());
+        assert!(cache.get("max-age", &headers("no-cache")).is_none());
+    }
+}
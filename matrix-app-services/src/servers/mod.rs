@@ -3,3 +3,16 @@ pub(crate) mod appservice;
 
 ///
 pub(crate) mod proxy;
+
+///
+pub(crate) mod admin;
+
+///
+pub(crate) mod scheduler;
+
+///
+pub(crate) mod cache;
+
+///
+pub(crate) mod breaker;
+
@@ -0,0 +1,257 @@
+use std::{ collections::{ HashMap, VecDeque }, sync::Arc, time::{ Duration, Instant } };
+
+use parking_lot::Mutex;
+use tokio::sync::oneshot;
+
+use crate::{ metrics::Metrics, types::SchedulingPolicy };
+
+#[derive(Debug)]
+struct UserQueue {
+    active: usize,
+    tokens: f64,
+    refilled: Instant,
+    waiting: VecDeque<oneshot::Sender<Permit>>,
+}
+
+impl UserQueue {
+    fn new(policy: &SchedulingPolicy) -> Self {
+        Self { active: 0, tokens: policy.per_user_rps.max(1.0), refilled: Instant::now(), waiting: VecDeque::new() }
+    }
+
+    fn refill(&mut self, policy: &SchedulingPolicy) {
+        if policy.per_user_rps > 0.0 {
+            let now = Instant::now();
+            let earned = now.duration_since(self.refilled).as_secs_f64() * policy.per_user_rps;
+            self.tokens = (self.tokens + earned).min(policy.per_user_rps.max(1.0));
+            self.refilled = now;
+        }
+    }
+
+    /// Nothing in flight, nothing waiting and no rate limit debt, so the queue can be forgotten
+    fn is_idle(&mut self, policy: &SchedulingPolicy) -> bool {
+        self.refill(policy);
+        self.active == 0 && self.waiting.is_empty() && (policy.per_user_rps <= 0.0 || self.tokens >= policy.per_user_rps.max(1.0))
+    }
+
+    /// How long until this user may send again based on its rate limit alone
+    fn rate_delay(&self, policy: &SchedulingPolicy) -> Option<Duration> {
+        if policy.per_user_rps > 0.0 && self.tokens < 1.0 {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / policy.per_user_rps))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct SchedulerState {
+    users: HashMap<String, UserQueue>,
+    ready: VecDeque<String>,
+    active: usize,
+    queued: usize,
+    timer_armed: bool,
+}
+
+/// Fair, per-user limited admission of outgoing proxy requests
+#[derive(Clone, Debug)]
+pub(crate) struct Scheduler {
+    policy: SchedulingPolicy,
+    state: Arc<Mutex<SchedulerState>>,
+    metrics: Metrics,
+}
+
+/// Admission to send one request. Frees the slot when dropped.
+#[derive(Debug)]
+pub(crate) struct Permit {
+    scheduler: Scheduler,
+    user: String,
+    armed: bool,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        {
+            let mut guard = self.scheduler.state.lock();
+            let state = &mut *guard;
+            state.active = state.active.saturating_sub(1);
+            if let Some(queue) = state.users.get_mut(&self.user) {
+                queue.active = queue.active.saturating_sub(1);
+                if queue.is_idle(&self.scheduler.policy) {
+                    let _ = state.users.remove(&self.user);
+                }
+            }
+        }
+        self.scheduler.dispatch();
+    }
+}
+
+impl Scheduler {
+    pub fn new(policy: SchedulingPolicy, metrics: Metrics) -> Self {
+        Self { policy, state: Arc::new(Mutex::new(SchedulerState::default())), metrics }
+    }
+
+    /// Waits until `user` may send a request
+    pub async fn acquire(&self, user: impl Into<String>) -> Option<Permit> {
+        let user = user.into();
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut guard = self.state.lock();
+            let state = &mut *guard;
+            let queue = state.users.entry(user.clone()).or_insert_with(|| UserQueue::new(&self.policy));
+            let newly_ready = queue.waiting.is_empty();
+            queue.waiting.push_back(sender);
+            if newly_ready {
+                state.ready.push_back(user);
+            }
+            state.queued += 1;
+        }
+
+        let started = Instant::now();
+        self.dispatch();
+        let permit = receiver.await.ok();
+        self.metrics.proxy_queue_wait.observe(started.elapsed().as_secs_f64());
+        permit
+    }
+
+    /// Grants as many waiting requests as the limits allow, visiting users round-robin
+    fn dispatch(&self) {
+        let mut guard = self.state.lock();
+        let state = &mut *guard;
+        let mut next_refill: Option<Duration> = None;
+        let mut blocked = 0;
+
+        while blocked < state.ready.len() {
+            if self.policy.global_concurrency > 0 && state.active >= self.policy.global_concurrency {
+                break;
+            }
+
+            let Some(user) = state.ready.pop_front() else {
+                break;
+            };
+            let Some(queue) = state.users.get_mut(&user) else {
+                continue;
+            };
+
+            queue.refill(&self.policy);
+            if queue.active >= self.policy.per_user_concurrency.max(1) {
+                state.ready.push_back(user);
+                blocked += 1;
+                continue;
+            }
+            if let Some(delay) = queue.rate_delay(&self.policy) {
+                next_refill = Some(next_refill.map_or(delay, |current| current.min(delay)));
+                state.ready.push_back(user);
+                blocked += 1;
+                continue;
+            }
+
+            // Skip requests whose caller has given up waiting
+            let mut granted = false;
+            while let Some(waiter) = queue.waiting.pop_front() {
+                state.queued = state.queued.saturating_sub(1);
+                let permit = Permit { scheduler: self.clone(), user: user.clone(), armed: true };
+                match waiter.send(permit) {
+                    Ok(()) => {
+                        granted = true;
+                        break;
+                    }
+                    Err(mut permit) => permit.armed = false,
+                }
+            }
+
+            if granted {
+                queue.active += 1;
+                if self.policy.per_user_rps > 0.0 {
+                    queue.tokens -= 1.0;
+                }
+                state.active += 1;
+                blocked = 0;
+            }
+
+            if !queue.waiting.is_empty() {
+                state.ready.push_back(user);
+            } else if queue.is_idle(&self.policy) {
+                let _ = state.users.remove(&user);
+            }
+        }
+
+        self.metrics.proxy_queued.set(state.queued as i64);
+        self.metrics.proxy_queued_users.set(state.ready.len() as i64);
+
+        if let Some(delay) = next_refill && !state.timer_armed {
+            state.timer_armed = true;
+            drop(guard);
+            let scheduler = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                scheduler.state.lock().timer_armed = false;
+                scheduler.dispatch();
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ sync::Arc, time::{ Duration, Instant } };
+
+    use parking_lot::Mutex;
+
+    use super::Scheduler;
+    use crate::{ metrics::Metrics, types::SchedulingPolicy };
+
+    #[tokio::test]
+    async fn flooding_user_does_not_starve_others() {
+        let policy = SchedulingPolicy { per_user_concurrency: 1, per_user_rps: 0.0, global_concurrency: 1 };
+        let scheduler = Scheduler::new(policy, Metrics::new());
+        let granted = Arc::new(Mutex::new(Vec::new()));
+        let spawn = |user: &'static str| {
+            let (scheduler, granted) = (scheduler.clone(), granted.clone());
+            tokio::spawn(async move {
+                let permit = scheduler.acquire(user).await;
+                granted.lock().push(user);
+                drop(permit);
+            })
+        };
+
+        let first = scheduler.acquire("flood").await.unwrap();
+        let mut tasks = (0..10).map(|_| spawn("flood")).collect::<Vec<_>>();
+        tokio::task::yield_now().await;
+        tasks.push(spawn("quiet"));
+        tokio::task::yield_now().await;
+        assert!(granted.lock().is_empty());
+
+        drop(first);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        // Queued behind ten requests of the other user, but served right after the one that was already next
+        assert_eq!(granted.lock()[..2], ["flood", "quiet"]);
+        assert_eq!(granted.lock().len(), 11);
+    }
+
+    #[tokio::test]
+    async fn limits_concurrency_and_rate_per_user() {
+        let policy = SchedulingPolicy { per_user_concurrency: 2, per_user_rps: 10.0, global_concurrency: 0 };
+        let scheduler = Scheduler::new(policy, Metrics::new());
+
+        let first = scheduler.acquire("user").await.unwrap();
+        let _second = scheduler.acquire("user").await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(20), scheduler.acquire("user")).await.is_err());
+        // Other users have their own limits
+        let _other = scheduler.acquire("other").await.unwrap();
+        drop(first);
+
+        // A burst of up to one second's worth, then one request per 100ms
+        for _ in 2..10 {
+            drop(scheduler.acquire("user").await.unwrap());
+        }
+        let started = Instant::now();
+        drop(scheduler.acquire("user").await.unwrap());
+        assert!(started.elapsed() >= Duration::from_millis(50), "{:?}", started.elapsed());
+    }
+}
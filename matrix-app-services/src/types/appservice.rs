@@ -88,7 +88,9 @@ impl AppserviceEventKind {
     }
 }
 
+/// An event pushed to the appservice by the homeserver
 #[derive(Clone, Debug)]
+#[allow(missing_docs)]
 pub enum AppserviceEvent {
     Push(matrix_sdk::ruma::api::appservice::event::push_events::v1::Request),
     Ping(matrix_sdk::ruma::api::appservice::ping::send_ping::v1::Request),
@@ -0,0 +1,65 @@
+use bon::Builder;
+use chrono::{ DateTime, Utc };
+use serde::{ Deserialize, Serialize };
+
+/// A write performed through the internal proxy
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// When the homeserver answered
+    pub timestamp: DateTime<Utc>,
+
+    /// User the request was made as (the sender user for the service client)
+    pub user_id: String,
+
+    /// HTTP method
+    pub method: String,
+
+    /// Endpoint template, like `/_matrix/client/v3/rooms/{roomId}/send/{param}/{param}`
+    pub endpoint: String,
+
+    /// Request path, without the homeserver's path prefix
+    pub path: String,
+
+    /// Room the request was about, if any
+    pub room_id: Option<String>,
+
+    /// Event ID returned by the homeserver, if any
+    pub event_id: Option<String>,
+
+    /// HTTP status returned by the homeserver
+    pub status: u16,
+}
+
+/// Filters for [Appservice::audit_log](crate::Appservice::audit_log). Unset filters match everything.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Builder)]
+pub struct AuditQuery {
+    /// Only writes made as this user
+    #[builder(into)]
+    pub user_id: Option<String>,
+
+    /// Only writes about this room
+    #[builder(into)]
+    pub room_id: Option<String>,
+
+    /// Only writes at or after this time
+    pub since: Option<DateTime<Utc>>,
+
+    /// Only writes before this time
+    pub until: Option<DateTime<Utc>>,
+
+    /// Maximum number of records, oldest first
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    /// Whether `record` passes the user & room filters
+    pub fn matches(&self, record: &AuditRecord) -> bool {
+        self.user_id.as_ref().is_none_or(|user_id| *user_id == record.user_id)
+            && self.room_id.as_ref().is_none_or(|room_id| record.room_id.as_ref() == Some(room_id))
+    }
+}
+
+/// Sled key for a record at `timestamp`, ordered by time. `id` keeps records within the same millisecond apart.
+pub(crate) fn audit_key(timestamp: DateTime<Utc>, id: u64) -> String {
+    format!("{:020}-{:020}", timestamp.timestamp_millis().max(0), id)
+}
@@ -6,6 +6,8 @@ use ruma::api::appservice as ruma_as;
 use serde::{ Deserialize, Serialize };
 use url::Url;
 
+use crate::types::Secret;
+
 /// An enum defining the possible types of [Namespace]
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "snake_case")]
@@ -66,12 +68,25 @@ pub struct PortRange {
 }
 
 impl PortRange {
-    /// Gets an open port in the specified range
+    /// Gets an open port in the specified range. The port may be taken by the time it's bound, prefer [PortRange::bind].
     pub fn pick(&self) -> u16 {
         openport
             ::pick_unused_port(Range::<u16>::from(self.clone()))
             .expect("No ports open in the specified range")
     }
+
+    /// Binds a loopback listener to the first free port in the range. A range of `(0, 0)` lets the OS assign a port.
+    pub fn bind(&self) -> std::io::Result<std::net::TcpListener> {
+        let mut last_error = None;
+        for port in self.low..=self.high {
+            match std::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], port))) {
+                Ok(listener) => return Ok(listener),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::AddrNotAvailable, "Empty port range")))
+    }
 }
 
 impl Default for PortRange {
@@ -98,6 +113,217 @@ impl From<PortRange> for Range<u16> {
     }
 }
 
+/// How virtual clients reach the internal proxy.
+///
+/// Every transport is still a second HTTP hop through the proxy, which authorizes & re-issues each request: matrix-sdk only
+/// accepts a plain [reqwest::Client], which has no request-level hook to apply the [ProxiedEntity](crate::types::ProxiedEntity)
+/// authorization inside the client. Transports only change what the hop to the proxy costs.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyTransport {
+    /// HTTPS over loopback TCP, using a self-signed certificate generated at startup
+    #[default]
+    Tls,
+
+    /// Plain HTTP over a private Unix domain socket. Skips the TCP & TLS handshakes to the proxy, but not the proxy itself;
+    /// requests are still upgraded to the homeserver's scheme before leaving the proxy.
+    Unix,
+}
+
+/// A PEM-encoded certificate/key pair on disk
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TlsFiles {
+    /// Path to the certificate chain (PEM)
+    pub certificate: PathBuf,
+
+    /// Path to the private key (PEM)
+    pub key: PathBuf,
+}
+
+impl TlsFiles {
+    /// Creates a new [TlsFiles] from a certificate and key path
+    pub fn new(certificate: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        Self { certificate: certificate.into(), key: key.into() }
+    }
+}
+
+/// Automatic retry of rate-limited (`429 M_LIMIT_EXCEEDED`) requests by the internal proxy
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts per request, including the first one
+    pub max_attempts: u32,
+
+    /// Maximum total time (ms) spent on a single request, including waiting. A retry that would end after this is not attempted.
+    pub deadline_ms: u64,
+
+    /// Delay (ms) used when the homeserver specifies neither `retry_after_ms` nor `Retry-After`
+    pub default_delay_ms: u64,
+
+    /// Random extra delay as a fraction of the delay (`0.25` waits up to 25% longer)
+    pub jitter: f64,
+
+    /// Largest request body (bytes) that is buffered so it can be replayed. Larger or unsized bodies are streamed and never retried.
+    pub max_buffered_body: usize,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            deadline_ms: 60_000,
+            default_delay_ms: 1_000,
+            jitter: 0.25,
+            max_buffered_body: 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// Per-user limits for requests leaving the internal proxy. Users with queued requests are served round-robin.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SchedulingPolicy {
+    /// Maximum in-flight requests per masqueraded user
+    pub per_user_concurrency: usize,
+
+    /// Maximum requests per second per masqueraded user (with bursts of up to one second's worth). `0` disables the limit.
+    pub per_user_rps: f64,
+
+    /// Maximum in-flight requests overall. `0` disables the limit.
+    pub global_concurrency: usize,
+}
+
+impl Default for SchedulingPolicy {
+    fn default() -> Self {
+        Self {
+            per_user_concurrency: 4,
+            per_user_rps: 0.0,
+            global_concurrency: 64,
+        }
+    }
+}
+
+/// Failing fast while the homeserver is down, instead of letting every request wait for its own timeout
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CircuitBreakerPolicy {
+    /// Consecutive failed upstream requests (connection errors, timeouts, 502, 503 & 504) after which the circuit opens
+    pub failure_threshold: u32,
+
+    /// Interval (ms) between `/versions` probes while the circuit is open. The circuit closes after the first successful probe.
+    pub probe_interval_ms: u64,
+}
+
+impl Default for CircuitBreakerPolicy {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            probe_interval_ms: 5_000,
+        }
+    }
+}
+
+/// Persistent log of writes performed through the internal proxy
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct AuditPolicy {
+    /// Seconds records are kept. `0` keeps them forever.
+    pub retention_secs: u64,
+}
+
+impl Default for AuditPolicy {
+    fn default() -> Self {
+        Self { retention_secs: 90 * 24 * 60 * 60 }
+    }
+}
+
+/// In-memory caching of idempotent reads in the internal proxy
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ResponseCachePolicy {
+    /// Endpoints whose GET responses are cached, as templates like `/_matrix/client/v3/rooms/{roomId}/joined_members`
+    /// (IDs replaced by `{roomId}`, `{userId}`, `{roomAlias}` & `{eventId}`, other opaque segments by `{param}`)
+    pub endpoints: Vec<String>,
+
+    /// Seconds a response is kept, shortened by the response's `Cache-Control: max-age`
+    pub ttl_secs: u64,
+
+    /// Maximum cached responses. `0` disables the limit.
+    pub max_entries: usize,
+
+    /// Largest response body (bytes) that is cached. Responses without a declared size are never cached.
+    pub max_body: usize,
+}
+
+impl Default for ResponseCachePolicy {
+    fn default() -> Self {
+        Self {
+            endpoints: [
+                "/_matrix/client/v3/profile/{userId}",
+                "/_matrix/client/v3/profile/{userId}/displayname",
+                "/_matrix/client/v3/profile/{userId}/avatar_url",
+                "/_matrix/client/v3/rooms/{roomId}/joined_members",
+                "/_matrix/client/v3/rooms/{roomId}/members",
+                "/_matrix/client/v3/rooms/{roomId}/state",
+                "/_matrix/client/v3/rooms/{roomId}/state/{param}",
+                "/_matrix/client/v3/rooms/{roomId}/state/{param}/{param}",
+            ].into_iter().map(String::from).collect(),
+            ttl_secs: 30,
+            max_entries: 4096,
+            max_body: 1024 * 1024,
+        }
+    }
+}
+
+/// Limits for the [VirtualClient](crate::VirtualClient) media helpers
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct MediaPolicy {
+    /// Largest upload (bytes). Streams are cut off once they exceed it.
+    pub max_upload_size: u64,
+
+    /// Largest download (bytes). Downloads are refused if the announced size exceeds it, and cut off once they do.
+    pub max_download_size: u64,
+
+    /// Maximum entries in the upload cache, least recently used entries are evicted first. `0` disables the limit.
+    pub cache_max_entries: usize,
+
+    /// Seconds after which upload cache entries expire. `0` disables expiry.
+    pub cache_max_age_secs: u64,
+
+    /// Whether cached uploads are checked to still exist on the homeserver before being reused
+    pub cache_verify: bool,
+}
+
+impl Default for MediaPolicy {
+    fn default() -> Self {
+        Self {
+            max_upload_size: 50 * 1024 * 1024,
+            max_download_size: 50 * 1024 * 1024,
+            cache_max_entries: 10_000,
+            cache_max_age_secs: 0,
+            cache_verify: false,
+        }
+    }
+}
+
+/// Credentials for an external proxy
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProxyCredentials {
+    /// Proxy username
+    pub username: String,
+
+    /// Proxy password
+    pub password: Secret,
+}
+
+impl ProxyCredentials {
+    /// Creates new [ProxyCredentials]
+    pub fn new(username: impl Into<String>, password: impl Into<Secret>) -> Self {
+        Self { username: username.into(), password: password.into() }
+    }
+}
+
 /// Global configuration for the AppService
 #[derive(Serialize, Deserialize, Clone, Debug, Builder, CloneGetters)]
 #[getset(get_clone = "pub")]
@@ -117,20 +343,26 @@ pub struct Config {
     protocols: Vec<String>,
 
     /// A secret token that the application service will use to authenticate requests to the homeserver. By default, a new token is generated whenever Config is built.
-    #[builder(default = Config::generate_key(32), into)]
+    #[builder(default = Secret::from(Config::generate_key(32)), into)]
     #[serde(alias = "as_token")]
-    appservice_token: String,
+    appservice_token: Secret,
 
     /// A secret token that the homeserver will use authenticate requests to the application service. By default, a new token is generated whenever Config is built.
-    #[builder(default = Config::generate_key(32), into)]
+    #[builder(default = Secret::from(Config::generate_key(32)), into)]
     #[serde(alias = "hs_token")]
-    homeserver_token: String,
+    homeserver_token: Secret,
 
     /// Whether requests from masqueraded users are rate-limited. The sender is excluded.
     #[builder(default)]
     #[serde(default)]
     rate_limited: bool,
 
+    /// Whether bot requests act as the bot's own device (MSC3202 `device_id` masquerading), and ghosts may create devices without logging in (MSC4190).
+    /// Both need to be supported & enabled by the homeserver. [Appservice::register_user](crate::Appservice::register_user) creates the bot's device.
+    #[builder(default)]
+    #[serde(default)]
+    device_masquerading: bool,
+
     /// Whether the application service wants to receive ephemeral data.
     #[builder(default)]
     #[serde(default)]
@@ -148,7 +380,49 @@ pub struct Config {
     #[builder(into, default = ([0,0,0,0], 8080))]
     local_address: SocketAddr,
 
-    /// Ports to allow the internal proxy to bind to
+    /// Unix domain socket to bind the local server to. Takes priority over `local_address` (and `local_tls`) if set. Ignored if `url` is `None`.
+    #[builder(into)]
+    local_socket: Option<PathBuf>,
+
+    /// Certificate/key pair to serve the local server over HTTPS on `local_address`. Ignored if `url` is `None`.
+    #[builder(into)]
+    local_tls: Option<TlsFiles>,
+
+    /// Address to serve the health/readiness & metrics endpoints on. They are never exposed on the homeserver-facing listener, so they are disabled when unset.
+    #[builder(into)]
+    admin_address: Option<SocketAddr>,
+
+    /// How virtual clients connect to the internal proxy
+    #[builder(into, default)]
+    #[serde(default)]
+    proxy_transport: ProxyTransport,
+
+    /// Opt-in automatic retry of rate-limited requests in the internal proxy
+    #[builder(into)]
+    rate_limit_retry: Option<RetryPolicy>,
+
+    /// Opt-in per-user fair scheduling of requests leaving the internal proxy
+    #[builder(into)]
+    request_scheduling: Option<SchedulingPolicy>,
+
+    /// Size limits for media uploaded & downloaded by virtual clients
+    #[builder(into, default)]
+    #[serde(default)]
+    media: MediaPolicy,
+
+    /// Opt-in audit log of every write (non-GET/HEAD/OPTIONS request) through the internal proxy, see [Appservice::audit_log](crate::Appservice::audit_log)
+    #[builder(into)]
+    audit_log: Option<AuditPolicy>,
+
+    /// Opt-in circuit breaker in the internal proxy, see [Appservice::status](crate::Appservice::status)
+    #[builder(into)]
+    circuit_breaker: Option<CircuitBreakerPolicy>,
+
+    /// Opt-in caching of selected GET responses in the internal proxy. Writes through the proxy invalidate cached reads about the same room or user.
+    #[builder(into)]
+    response_cache: Option<ResponseCachePolicy>,
+
+    /// Ports to allow the internal proxy to bind to, `(0, 0)` for any free port. Ignored by [ProxyTransport::Unix].
     #[builder(into, default)]
     proxy_ports: PortRange,
 
@@ -156,13 +430,34 @@ pub struct Config {
     #[builder(into, default)]
     user_agent: String,
 
-    /// URL of homeserver (http(s)://...)
+    /// Base URL of the homeserver's client API (`http(s)://host[:port][/prefix]`). If unset, [Config::discover] looks it up from `server_name`.
+    #[builder(into)]
+    homeserver: Option<String>,
+
+    /// Name of the homeserver, as used in user IDs & room aliases. Defaults to the host of `homeserver`.
+    #[builder(into)]
+    #[getset(skip)]
+    server_name: Option<String>,
+
+    /// Hosts besides the homeserver (media repository, identity server, ...) the internal proxy may forward requests to, as `host` or `host:port`.
+    /// Requests for any other destination are refused, so the appservice token is never sent elsewhere.
+    #[builder(into, default)]
+    #[serde(default)]
+    allowed_hosts: Vec<String>,
+
+    /// URL of an external proxy to connect through (after internal proxy handling). Supports `http://`, `https://`, `socks5://` & `socks5h://`.
+    /// Kept secret, as it may embed credentials (`user:pass@`).
     #[builder(into)]
-    homeserver: String,
+    proxy: Option<Secret>,
 
-    /// URL of an external proxy to connect through (after internal proxy handling)
+    /// Credentials for the external proxy. Credentials embedded in the `proxy` URL are also honored.
     #[builder(into)]
-    proxy: Option<String>,
+    proxy_credentials: Option<ProxyCredentials>,
+
+    /// Hosts that bypass the external proxy, in `NO_PROXY` syntax (domains, `*.domain`, IPs & CIDR blocks)
+    #[builder(into, default)]
+    #[serde(default)]
+    no_proxy: Vec<String>,
 
     /// Persistent state path. Defaults to a temporary file if not provided.
     #[builder(into)]
@@ -228,8 +523,8 @@ impl Config {
         let reginit = ruma_as::RegistrationInit {
             id: self.app_id(),
             url: self.url(),
-            as_token: self.appservice_token(),
-            hs_token: self.homeserver_token(),
+            as_token: self.appservice_token().into_inner(),
+            hs_token: self.homeserver_token().into_inner(),
             sender_localpart: self.sender_localpart(),
             namespaces: namespaces,
             rate_limited: Some(self.rate_limited()),
@@ -240,24 +535,190 @@ impl Config {
         registration
     }
 
-    /// Get the homeserver URL
+    /// Get the homeserver base URL
     pub fn homeserver_url(&self) -> crate::Result<Url> {
-        if self.homeserver.starts_with("http") && self.homeserver.contains("://") {
-            Url::parse(&self.homeserver).or_else(|e| Err(crate::Error::url_parsing(self.homeserver(), e)))
+        let Some(homeserver) = self.homeserver.as_ref() else {
+            return Err(crate::Error::MissingHomeserver);
+        };
+        let url = if homeserver.starts_with("http") && homeserver.contains("://") {
+            Url::parse(homeserver).or_else(|e| Err(crate::Error::url_parsing(homeserver, e)))?
         } else {
-            Url::parse(&format!("https://{}", self.homeserver())).or_else(|e| Err(crate::Error::url_parsing(self.homeserver(), e)))
+            Url::parse(&format!("https://{}", homeserver)).or_else(|e| Err(crate::Error::url_parsing(homeserver, e)))?
+        };
+        if url.host_str().is_none() {
+            return Err(crate::Error::url_parsing(homeserver, url::ParseError::EmptyHost));
         }
+
+        Ok(url)
     }
 
     /// Get the homeserver name
     pub fn server_name(&self) -> String {
-        let url = self.homeserver_url().expect("Expected a valid server url/name");
-        url.host_str().expect("Expected a valid server name").to_string()
+        match &self.server_name {
+            Some(server_name) => server_name.clone(),
+            None => {
+                let url = self.homeserver_url().expect("Expected a valid server url/name");
+                url.host_str().expect("Expected a valid server name").to_string()
+            }
+        }
+    }
+
+    /// Starts an HTTP client for requests leaving the appservice, going through the external proxy unless the host is in `no_proxy`
+    pub(crate) fn upstream_client_builder(&self) -> crate::Result<reqwest::ClientBuilder> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(url) = self.proxy() {
+            let mut proxy = reqwest::Proxy::all(url.expose())?;
+            if let Some(credentials) = self.proxy_credentials() {
+                proxy = proxy.basic_auth(&credentials.username, credentials.password.expose());
+            }
+            proxy = proxy.no_proxy(reqwest::NoProxy::from_string(&self.no_proxy().join(",")));
+            builder = builder.proxy(proxy);
+        }
+
+        Ok(builder)
+    }
+
+    /// Looks up the homeserver base URL from `server_name` via `/.well-known/matrix/client`, if `homeserver` isn't set.
+    /// Falls back to `https://<server_name>` if the server doesn't publish a well-known file.
+    pub async fn discover(mut self) -> crate::Result<Self> {
+        if self.homeserver.is_some() {
+            return Ok(self);
+        }
+        let server_name = self.server_name.clone().ok_or(crate::Error::MissingHomeserver)?;
+
+        let well_known = format!("https://{}/.well-known/matrix/client", server_name);
+        let well_known_url = Url::parse(&well_known).or_else(|e| Err(crate::Error::url_parsing(&well_known, e)))?;
+        let response = self
+            .upstream_client_builder()?
+            .user_agent(self.user_agent())
+            .build()?
+            .get(well_known_url)
+            .send()
+            .await?;
+
+        let base_url = if response.status() == reqwest::StatusCode::NOT_FOUND {
+            format!("https://{}", server_name)
+        } else {
+            let body = response.error_for_status()?.json::<serde_json::Value>().await?;
+            body.get("m.homeserver")
+                .and_then(|v| v.get("base_url"))
+                .and_then(|v| v.as_str())
+                .ok_or(crate::Error::MissingHomeserver)?
+                .to_string()
+        };
+
+        let base_url = Url::parse(&base_url).or_else(|e| Err(crate::Error::url_parsing(&base_url, e)))?;
+        tracing::debug!(server_name = %server_name, homeserver = %base_url, "Discovered homeserver");
+        self.homeserver = Some(base_url.as_str().trim_end_matches('/').to_string());
+        Ok(self)
+    }
+
+    /// Whether `user_id` is the sender or inside one of the user namespaces (matched from the start, like homeservers do)
+    pub fn is_user_in_namespace(&self, user_id: impl AsRef<str>) -> bool {
+        let user_id = user_id.as_ref();
+        if user_id == format!("@{}:{}", self.sender_localpart, self.server_name()) {
+            return true;
+        }
+
+        self.namespaces
+            .iter()
+            .filter(|namespace| matches!(namespace.kind, NamespaceKind::User))
+            .filter_map(|namespace| match regex::Regex::new(&format!("^(?:{})", namespace.regex)) {
+                Ok(regex) => Some(regex),
+                Err(e) => {
+                    tracing::warn!(regex = %namespace.regex, error = %e, "Ignoring invalid user namespace");
+                    None
+                }
+            })
+            .any(|regex| regex.is_match(user_id))
+    }
+
+    /// Whether the internal proxy may forward requests to `url`.
+    /// The homeserver's host matches without a port too, as virtual clients leave it out for the proxy to restore.
+    pub fn is_allowed_destination(&self, url: &Url) -> bool {
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        if self.homeserver_url().is_ok_and(|homeserver| {
+            homeserver.host_str() == Some(host) && (url.port().is_none() || url.port() == homeserver.port_or_known_default())
+        }) {
+            return true;
+        }
+
+        let with_port = url.port_or_known_default().map(|port| format!("{host}:{port}"));
+        self.allowed_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host) || with_port.as_ref().is_some_and(|v| allowed.eq_ignore_ascii_case(v)))
     }
 
     /// Output the registration as YAML
     pub fn registration_yaml(&self) -> crate::Result<String> {
         let reg = self.registration();
-        Ok(serde_norway::to_string(&reg)?)
+        if !self.device_masquerading() {
+            return Ok(serde_norway::to_string(&reg)?);
+        }
+
+        // Unstable registration flags aren't part of the ruma type
+        let mut value = serde_norway::to_value(&reg)?;
+        if let serde_norway::Value::Mapping(mapping) = &mut value {
+            let _ = mapping.insert("io.element.msc4190".into(), true.into());
+        }
+        Ok(serde_norway::to_string(&value)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::{ Config, Namespace };
+
+    fn config(homeserver: &str, allowed_hosts: &[&str]) -> Config {
+        Config::builder("test")
+            .homeserver(homeserver)
+            .sender_localpart("bot")
+            .appservice_token("as_token")
+            .homeserver_token("hs_token")
+            .namespace(Namespace::user("@bot_.*"))
+            .allowed_hosts(allowed_hosts.iter().map(|host| host.to_string()).collect::<Vec<_>>())
+            .build()
+    }
+
+    fn allowed(config: &Config, url: &str) -> bool {
+        config.is_allowed_destination(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn homeserver_is_allowed_on_its_own_port() {
+        let config = config("https://matrix.example.org:8448", &[]);
+        assert!(allowed(&config, "https://matrix.example.org/_matrix/client/versions"));
+        assert!(allowed(&config, "https://matrix.example.org:8448/_matrix/client/versions"));
+        assert!(allowed(&config, "https://MATRIX.Example.org/_matrix/client/versions"));
+        assert!(!allowed(&config, "https://matrix.example.org:9000/_matrix/client/versions"));
+    }
+
+    #[test]
+    fn homeserver_default_port_matches_explicit_port() {
+        let config = config("http://matrix.example.org", &[]);
+        assert!(allowed(&config, "https://matrix.example.org:80/"));
+        assert!(!allowed(&config, "https://matrix.example.org:8008/"));
+    }
+
+    #[test]
+    fn other_hosts_are_refused() {
+        let config = config("https://matrix.example.org", &[]);
+        assert!(!allowed(&config, "https://example.org/"));
+        assert!(!allowed(&config, "https://matrix.example.org.evil.test/"));
+        assert!(!allowed(&config, "https://127.0.0.1/"));
+    }
+
+    #[test]
+    fn allowed_hosts_match_host_or_host_and_port() {
+        let config = config("https://matrix.example.org", &["Media.Example.org", "identity.example.org:8090"]);
+        assert!(allowed(&config, "https://media.example.org/_matrix/media/v3/config"));
+        assert!(allowed(&config, "https://media.example.org:8443/_matrix/media/v3/config"));
+        assert!(allowed(&config, "https://IDENTITY.example.org:8090/_matrix/identity/v2"));
+        assert!(!allowed(&config, "https://identity.example.org/_matrix/identity/v2"));
+        assert!(!allowed(&config, "https://identity.example.org:8091/_matrix/identity/v2"));
     }
 }
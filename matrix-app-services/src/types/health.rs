@@ -0,0 +1,90 @@
+use chrono::{ DateTime, Utc };
+use serde::{ Deserialize, Serialize };
+
+/// The result of a single readiness check
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// Whether this check passed
+    pub ok: bool,
+
+    /// Why this check failed, if it did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl HealthCheck {
+    /// Creates a passing [HealthCheck]
+    pub fn passed() -> Self {
+        Self { ok: true, error: None }
+    }
+
+    /// Creates a failing [HealthCheck]
+    pub fn failed(error: impl Into<String>) -> Self {
+        Self { ok: false, error: Some(error.into()) }
+    }
+}
+
+impl<T, E: std::fmt::Display> From<Result<T, E>> for HealthCheck {
+    fn from(value: Result<T, E>) -> Self {
+        match value {
+            Ok(_) => Self::passed(),
+            Err(e) => Self::failed(e.to_string()),
+        }
+    }
+}
+
+/// Whether the homeserver is considered reachable, as tracked by the proxy's circuit breaker
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum HomeserverStatus {
+    /// Requests are passed through
+    Available,
+
+    /// Requests fail fast with [Error::HomeserverUnavailable](crate::Error::HomeserverUnavailable) until a probe succeeds
+    Unavailable {
+        /// When the circuit opened
+        since: DateTime<Utc>,
+
+        /// Consecutive failed upstream requests
+        failures: u32,
+    },
+}
+
+/// Point-in-time status of an [Appservice](crate::Appservice)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Status {
+    /// Homeserver availability. Always [HomeserverStatus::Available] without a circuit breaker.
+    pub homeserver: HomeserverStatus,
+
+    /// Cached virtual clients
+    pub virtual_clients: usize,
+}
+
+/// Individual readiness checks of an [Appservice](crate::Appservice)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ReadinessChecks {
+    /// The internal proxy is accepting connections
+    pub proxy: HealthCheck,
+
+    /// The homeserver answers `whoami` for the service client
+    pub homeserver: HealthCheck,
+
+    /// The sled state database is open & readable
+    pub state: HealthCheck,
+}
+
+/// Readiness report of an [Appservice](crate::Appservice)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Readiness {
+    /// Whether all checks passed
+    pub ready: bool,
+
+    /// The individual checks
+    pub checks: ReadinessChecks,
+}
+
+impl From<ReadinessChecks> for Readiness {
+    fn from(checks: ReadinessChecks) -> Self {
+        Self { ready: checks.proxy.ok && checks.homeserver.ok && checks.state.ok, checks }
+    }
+}
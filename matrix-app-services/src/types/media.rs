@@ -0,0 +1,101 @@
+use chrono::{ DateTime, Utc };
+use futures_util::{ stream::BoxStream, Stream, StreamExt, TryStreamExt };
+use matrix_sdk::{ bytes::{ Bytes, BytesMut }, ruma::OwnedMxcUri };
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+
+/// Fallback content type for media that can't be identified
+pub(crate) const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// A previously uploaded piece of media in the upload cache, keyed by content hash
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MediaCacheEntry {
+    /// Where the content was uploaded to
+    pub uri: OwnedMxcUri,
+
+    /// Content type it was uploaded with
+    pub content_type: String,
+
+    /// Size in bytes
+    pub size: u64,
+
+    /// When it was uploaded
+    pub uploaded_at: DateTime<Utc>,
+
+    /// When it was last reused (or uploaded)
+    pub last_used: DateTime<Utc>,
+}
+
+impl MediaCacheEntry {
+    /// Whether this entry is older than `max_age_secs` (`0` never expires)
+    pub fn is_expired(&self, max_age_secs: u64) -> bool {
+        max_age_secs > 0 && Utc::now().signed_duration_since(self.uploaded_at).num_seconds() > max_age_secs as i64
+    }
+}
+
+/// Cache key for `data`
+pub(crate) fn content_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Media downloaded through [VirtualClient::download_media](crate::VirtualClient::download_media), streamed as it arrives
+pub struct MediaDownload {
+    /// Content type reported by the homeserver, or sniffed from the first bytes if it didn't report one
+    pub content_type: String,
+
+    /// Size announced by the homeserver, if any
+    pub content_length: Option<u64>,
+
+    /// The content. Yields [Error::MediaTooLarge](crate::Error::MediaTooLarge) once the download size limit is exceeded.
+    pub stream: BoxStream<'static, crate::Result<Bytes>>,
+}
+
+impl std::fmt::Debug for MediaDownload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MediaDownload")
+            .field("content_type", &self.content_type)
+            .field("content_length", &self.content_length)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MediaDownload {
+    /// Collects the whole content into memory
+    pub async fn bytes(self) -> crate::Result<Bytes> {
+        let mut buffer = BytesMut::with_capacity(self.content_length.unwrap_or_default() as usize);
+        let mut stream = self.stream;
+        while let Some(chunk) = stream.next().await {
+            buffer.extend_from_slice(&chunk?);
+        }
+
+        Ok(buffer.freeze())
+    }
+}
+
+/// Ends `stream` with [Error::MediaTooLarge](crate::Error::MediaTooLarge) once more than `limit` bytes passed through
+pub(crate) fn limited<S, E: 'static>(stream: S, limit: u64) -> impl Stream<Item = crate::Result<Bytes>> + Send + 'static
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    crate::Error: From<E>,
+{
+    let mut seen = 0u64;
+    stream.map_err(crate::Error::from).and_then(move |chunk| {
+        seen += chunk.len() as u64;
+        let result = if seen > limit { Err(crate::Error::MediaTooLarge(limit)) } else { Ok(chunk) };
+        async move { result }
+    })
+}
+
+/// Reads the first chunk of `stream` to guess its content type, returning the stream with that chunk put back
+pub(crate) async fn sniff<S>(stream: S) -> crate::Result<(Option<String>, BoxStream<'static, crate::Result<Bytes>>)>
+where
+    S: Stream<Item = crate::Result<Bytes>> + Send + 'static,
+{
+    let mut stream = stream.boxed();
+    let Some(first) = stream.next().await.transpose()? else {
+        return Ok((None, futures_util::stream::empty().boxed()));
+    };
+
+    let content_type = infer::get(&first).map(|kind| kind.mime_type().to_string());
+    Ok((content_type, futures_util::stream::once(async move { Ok(first) }).chain(stream).boxed()))
+}
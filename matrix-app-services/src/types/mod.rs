@@ -1,6 +1,6 @@
 ///
 pub mod config;
-pub use config::{ Config, Namespace };
+pub use config::{ AuditPolicy, CircuitBreakerPolicy, Config, MediaPolicy, Namespace, ProxyCredentials, ProxyTransport, ResponseCachePolicy, RetryPolicy, SchedulingPolicy, TlsFiles };
 
 ///
 mod state;
@@ -10,8 +10,24 @@ pub use state::State;
 pub mod user;
 
 ///
-pub(crate) mod proxy;
-pub(crate) use proxy::{ProxyDirective, ProxyDirectiveTarget};
+pub mod proxy;
+pub use proxy::{ProxiedEntity, ProxyDirective, ProxyDirectiveTarget};
 
 ///
-pub mod appservice;
\ No newline at end of file
+pub mod appservice;
+
+///
+pub mod secret;
+pub use secret::Secret;
+
+///
+pub mod media;
+pub use media::{ MediaCacheEntry, MediaDownload };
+
+///
+pub mod audit;
+pub use audit::{ AuditQuery, AuditRecord };
+
+///
+pub mod health;
+pub use health::{ HealthCheck, HomeserverStatus, Readiness, Status };
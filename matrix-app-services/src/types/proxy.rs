@@ -1,32 +1,116 @@
 use std::net::SocketAddr;
 
+use matrix_sdk::ruma::MilliSecondsSinceUnixEpoch;
 use reqwest::dns::{Addrs, Resolve};
 use serde::{ Deserialize, Serialize };
 
+use crate::types::Secret;
+
+/// Who a request through the internal proxy is made as. Available to [proxy layers](crate::Appservice::proxy_layer) as a request extension.
+#[derive(Clone, Debug)]
+pub enum ProxiedEntity {
+    /// The service client
+    Service {
+        /// Appservice token sent to the homeserver
+        authorization: Secret
+    },
+
+    /// A bot client, or a request masqueraded as another user
+    Bot {
+        /// Appservice token sent to the homeserver
+        authorization: Secret,
+
+        /// User the request is made as
+        user_id: String,
+
+        /// Device the request is made as, with [Config::device_masquerading](crate::Config::device_masquerading)
+        device_id: Option<String>
+    }
+}
+
+impl ProxiedEntity {
+    /// The user this request is masqueraded as, if not the service user
+    pub fn user_id(&self) -> Option<&str> {
+        match self {
+            ProxiedEntity::Service { .. } => None,
+            ProxiedEntity::Bot { user_id, .. } => Some(user_id),
+        }
+    }
+
+    /// Masquerades this entity as `user_id` (without a device), keeping its authorization
+    pub(crate) fn masquerade(self, user_id: impl Into<String>) -> Self {
+        match self {
+            ProxiedEntity::Service { authorization } | ProxiedEntity::Bot { authorization, .. } =>
+                ProxiedEntity::Bot { authorization, user_id: user_id.into(), device_id: None },
+        }
+    }
+
+    /// Metrics label for this entity
+    pub(crate) fn role(&self) -> &'static str {
+        match self {
+            ProxiedEntity::Service { .. } => "service",
+            ProxiedEntity::Bot { .. } => "bot",
+        }
+    }
+}
+
+/// The request a [ProxyDirective] applies to
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
-pub(crate) enum ProxyDirectiveTarget {
+pub enum ProxyDirectiveTarget {
+    /// A request made by the service client
     Service {
+        /// Percent-decoded request path, without query
         path: String,
     },
+
+    /// A request made by a bot client
     Bot {
-        token: String,
+        /// The bot's localpart
+        localpart: String,
+
+        /// Percent-decoded request path, without query
         path: String,
     },
 }
 
 impl ProxyDirectiveTarget {
-    pub fn service(path: impl Into<String>) -> Self {
-        Self::Service { path: path.into() }
+    /// Normalizes a request path so that encoded & unencoded forms of the same path match
+    fn normalize(path: impl AsRef<str>) -> String {
+        let path = path.as_ref();
+        let path = path.split_once('?').map(|(path, _)| path).unwrap_or(path);
+        percent_encoding::percent_decode_str(path).decode_utf8_lossy().to_string()
     }
 
-    pub fn bot(path: impl Into<String>, token: impl Into<String>) -> Self {
-        Self::Bot { token: token.into(), path: path.into() }
+    /// Targets a request made by the service client
+    pub fn service(path: impl AsRef<str>) -> Self {
+        Self::Service { path: Self::normalize(path) }
+    }
+
+    /// Targets a request made by the bot client with `localpart`
+    pub fn bot(path: impl AsRef<str>, localpart: impl Into<String>) -> Self {
+        Self::Bot { localpart: localpart.into(), path: Self::normalize(path) }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub(crate) enum ProxyDirective {
+/// A one-shot modification the proxy applies to the next matching request
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyDirective {
+    /// Forward the request without masquerading (no `user_id` or device). The client's own `Authorization` header,
+    /// which is the appservice token for virtual clients, is passed through unchanged.
     DoNotModify,
+
+    /// Masquerade as a different user (full user ID) than the client's own
+    UserId(String),
+
+    /// Add the appservice-only `ts` query parameter (milliseconds since the unix epoch)
+    Timestamp(MilliSecondsSinceUnixEpoch),
+
+    /// Add a query parameter
+    Query(String, String),
+
+    /// Add (or replace) a header
+    Header(String, String),
 }
 
 #[derive(Debug)]
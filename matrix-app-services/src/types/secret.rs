@@ -0,0 +1,66 @@
+use std::fmt::{ Debug, Display };
+
+use serde::{ Deserialize, Serialize };
+
+/// A secret value (token, key, ...) that is redacted in [Debug] & [Display] output, but serialized as-is.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Default)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    /// Wraps a secret value
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    /// Exposes the secret value. Avoid passing the result anywhere it may be logged.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Unwraps the secret value
+    pub fn into_inner(self) -> String {
+        self.0
+    }
+
+    /// Compares against a candidate value in constant time (relative to the candidate's length)
+    pub fn matches(&self, candidate: impl AsRef<str>) -> bool {
+        let expected = self.0.as_bytes();
+        let candidate = candidate.as_ref().as_bytes();
+        if expected.len() != candidate.len() {
+            return false;
+        }
+
+        expected.iter().zip(candidate).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+impl Display for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Self(value.to_string())
+    }
+}
+
+impl From<Secret> for String {
+    fn from(value: Secret) -> Self {
+        value.0
+    }
+}
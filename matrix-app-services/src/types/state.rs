@@ -60,6 +60,36 @@ impl<V: Serialize + DeserializeOwned> State<V> {
             })
     }
 
+    /// Returns an iterator over all records in this State, skipping unreadable ones
+    pub fn entries(&self) -> impl Iterator<Item = (String, V)> {
+        self.0
+            .iter()
+            .filter_map(|entry| {
+                let (key, value) = entry.ok()?;
+                Some((String::from_utf8(key.to_vec()).ok()?, ciborium::from_reader::<V, _>(value.reader()).ok()?))
+            })
+    }
+
+    /// Returns an iterator over the records with keys in `[start, end)`, in key order, skipping unreadable ones
+    pub fn range(&self, start: impl AsRef<str>, end: impl AsRef<str>) -> impl Iterator<Item = (String, V)> {
+        self.0
+            .range(start.as_ref().as_bytes().to_vec()..end.as_ref().as_bytes().to_vec())
+            .filter_map(|entry| {
+                let (key, value) = entry.ok()?;
+                Some((String::from_utf8(key.to_vec()).ok()?, ciborium::from_reader::<V, _>(value.reader()).ok()?))
+            })
+    }
+
+    /// Number of records in this State
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether this State has no records
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
     /// Flushes this State
     pub fn flush(&self) -> crate::Result<usize> {
         Ok(self.0.flush()?)
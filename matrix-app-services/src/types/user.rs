@@ -1,13 +1,17 @@
+use std::collections::HashMap;
+
 use getset::CloneGetters;
 use matrix_sdk::ruma::{ DeviceId, UserId, ServerName, OwnedUserId, OwnedDeviceId };
 use serde::{ Deserialize, Serialize };
 
+use crate::types::Secret;
+
 /// An appservice-managed user
 #[derive(Serialize, Deserialize, Clone, Debug, CloneGetters)]
 pub struct UserRecord {
     /// Proxy access token
     #[getset(get_clone = "pub(crate)")]
-    token: String,
+    token: Secret,
 
     /// Matrix user id
     #[getset(get_clone = "pub with_prefix")]
@@ -16,24 +20,53 @@ pub struct UserRecord {
     /// Matrix device id
     #[getset(get_clone = "pub with_prefix")]
     device_id: String,
+
+    /// Last applied global profile
+    #[getset(get_clone = "pub")]
+    #[serde(default)]
+    profile: Profile,
+
+    /// Last applied per-room profile overrides, by room ID
+    #[getset(get_clone = "pub")]
+    #[serde(default)]
+    room_profiles: HashMap<String, Profile>,
+}
+
+/// A display name & avatar, as applied to the homeserver
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// Display name, if any
+    pub displayname: Option<String>,
+
+    /// Avatar `mxc://` URI, if any
+    pub avatar_url: Option<String>,
+}
+
+impl Profile {
+    /// Creates a new [Profile]
+    pub fn new(displayname: Option<impl Into<String>>, avatar_url: Option<impl Into<String>>) -> Self {
+        Self { displayname: displayname.map(|v| v.into()), avatar_url: avatar_url.map(|v| v.into()) }
+    }
 }
 
 impl UserRecord {
     /// Creates a new user with a random ID localpart
     pub fn new(server_name: impl AsRef<str>) -> Self {
         Self {
-            token: crate::generate_key(128),
+            token: Secret::from(crate::generate_key(128)),
             user_id: UserId::new(
                 &ServerName::parse(server_name).expect("Expected valid server_name")
             ).to_string(),
             device_id: DeviceId::new().to_string(),
+            profile: Profile::default(),
+            room_profiles: HashMap::new(),
         }
     }
 
     /// Creates a new user with a pre-set localpart
     pub fn new_with_id(localpart: impl AsRef<str>, server_name: impl AsRef<str>) -> Self {
         Self {
-            token: crate::generate_key(128),
+            token: Secret::from(crate::generate_key(128)),
             user_id: UserId::parse_with_server_name(
                 localpart.as_ref(),
                 &ServerName::parse(server_name).expect("Expected valid server_name")
@@ -41,9 +74,22 @@ impl UserRecord {
                 .expect("Failed to parse full user id")
                 .to_string(),
             device_id: DeviceId::new().to_string(),
+            profile: Profile::default(),
+            room_profiles: HashMap::new(),
         }
     }
 
+    /// Records a newly applied global profile. The homeserver propagates it to every room, replacing any overrides.
+    pub(crate) fn set_profile(&mut self, profile: Profile) {
+        self.profile = profile;
+        self.room_profiles.clear();
+    }
+
+    /// Records a newly applied per-room profile override
+    pub(crate) fn set_room_profile(&mut self, room_id: impl Into<String>, profile: Profile) {
+        let _ = self.room_profiles.insert(room_id.into(), profile);
+    }
+
     /// Gets the user_id as an [`OwnedUserId`]
     pub fn user_id(&self) -> OwnedUserId {
         UserId::parse(self.get_user_id()).expect("Should contain a valid UserId")
@@ -3,3 +3,9 @@ pub fn generate_key(length: usize) -> String {
         ::encode_key(genrs_lib::generate_key(length), genrs_lib::EncodingFormat::Base64)
         .expect("Key generation should succeed.")
 }
+
+/// Builds a Matrix-style JSON error response
+pub fn matrix_error(status: axum::http::StatusCode, errcode: &str, error: impl AsRef<str>) -> axum::response::Response {
+    use axum::response::IntoResponse;
+    (status, axum::Json(serde_json::json!({ "errcode": errcode, "error": error.as_ref() }))).into_response()
+}
@@ -1,15 +1,43 @@
-use std::ops::Deref;
+use std::{ fmt::Debug, ops::Deref };
 
 use matrix_sdk::{
     authentication::matrix::MatrixSession as Session,
-    ruma,
+    ruma::{
+        self,
+        api::{ error::FromHttpResponseError, IncomingResponse, OutgoingRequest, SendAccessToken },
+        api::client::{
+            device::{ get_devices, update_device, Device },
+            message::send_message_event,
+            profile::{ set_avatar_url, set_display_name },
+            error::ErrorKind,
+            state::{ get_state_event_for_key, send_state_event },
+        },
+        events::{ MessageLikeEventContent, StateEventContent, StateEventType },
+        serde::Raw,
+        MilliSecondsSinceUnixEpoch,
+    },
     Client,
     ClientBuilder,
     SessionMeta,
     SessionTokens,
 };
+use futures_util::{ Stream, StreamExt };
+use matrix_sdk::bytes::Bytes;
 use serde::{ Deserialize, Serialize };
 
+use crate::{
+    servers::proxy::TRACE_HEADER,
+    types::{ media, user::{ Profile, UserRecord }, MediaCacheEntry, MediaDownload, ProxyDirective, ProxyDirectiveTarget },
+};
+
+/// Passes the current span's ID to the proxy, which records it on its own span
+fn traced(request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    match tracing::Span::current().id() {
+        Some(id) => request.header(TRACE_HEADER, format!("{:x}", id.into_u64())),
+        None => request,
+    }
+}
+
 /// Whether this virtual client is a bot or the service user
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
 #[serde(rename_all = "snake_case")]
@@ -32,6 +60,7 @@ pub struct VirtualClientBuilder {
     http_client_builder: Option<reqwest::ClientBuilder>,
     log_in: bool,
     create_new: bool,
+    ensure_registered: bool,
     restored_session: Option<Session>,
 }
 
@@ -46,6 +75,7 @@ impl VirtualClientBuilder {
             http_client_builder: None,
             log_in: false,
             create_new: false,
+            ensure_registered: false,
             restored_session: None,
         }
     }
@@ -80,6 +110,12 @@ impl VirtualClientBuilder {
         self
     }
 
+    /// Register the bot user (see [Appservice::register_user](crate::Appservice::register_user)) if it isn't yet, instead of failing with [Error::UnregisteredUser](crate::Error::UnregisteredUser)
+    pub fn ensure_registered(mut self) -> Self {
+        self.ensure_registered = true;
+        self
+    }
+
     /// Restore a persisted session
     pub fn restored_session(mut self, session: Session) -> Self {
         self.restored_session = Some(session);
@@ -87,12 +123,11 @@ impl VirtualClientBuilder {
     }
 
     /// Build the resulting VirtualClient
+    #[tracing::instrument(name = "build_virtual_client", skip_all, fields(localpart = %self.localpart, user_id, kind))]
     pub async fn build(self) -> crate::Result<VirtualClient> {
-        println!("Building...");
-        if !self.create_new {
-            if let Some(client) = self.service.retrieve_client(self.localpart.clone()) {
-                return Ok(client);
-            }
+        if !self.create_new && let Some(client) = self.service.retrieve_client(self.localpart.clone()) {
+            tracing::trace!("Reusing cached client");
+            return Ok(client);
         }
 
         let user_id = ruma::UserId::parse_with_server_name(
@@ -105,8 +140,20 @@ impl VirtualClientBuilder {
             VirtualClientKind::Bot
         };
 
-        println!("Configuring...");
-        let internal_client = match client_kind {
+        tracing::Span::current()
+            .record("user_id", user_id.as_str())
+            .record("kind", tracing::field::debug(&client_kind));
+        if self.ensure_registered
+            && client_kind == VirtualClientKind::Bot
+            && self.service.state_user_records()?.get(self.localpart.clone())?.is_none()
+        {
+            tracing::debug!("Registering user before first use");
+            // Boxed, as registering builds the service client in turn
+            let _ = Box::pin(self.service.register_user(&self.localpart)).await?;
+        }
+
+        tracing::debug!("Configuring client");
+        let (internal_client, http_client) = match client_kind {
             VirtualClientKind::Bot =>
                 self.service.configure_bot_client(
                     self.localpart.clone(),
@@ -120,7 +167,7 @@ impl VirtualClientBuilder {
                 ).await?,
         };
 
-        println!("Setting up session");
+        tracing::debug!(login = self.log_in, restored = self.restored_session.is_some(), "Setting up session");
         let session = if let Some(session) = self.restored_session {
             session
         } else if self.log_in && client_kind != VirtualClientKind::Service {
@@ -142,13 +189,23 @@ impl VirtualClientBuilder {
 
             Session::from(&response)
         } else {
+            // Bots act as their recorded device, which the proxy masquerades as if enabled
+            let device_id = match (self.device_id, &client_kind) {
+                (Some(device_id), _) => device_id,
+                (None, VirtualClientKind::Bot) => self.service
+                    .state_user_records()?
+                    .get(self.localpart.clone())?
+                    .map(|record| record.device_id())
+                    .unwrap_or_else(ruma::DeviceId::new),
+                (None, VirtualClientKind::Service) => ruma::DeviceId::new(),
+            };
             Session {
                 meta: SessionMeta {
                     user_id: user_id.clone(),
-                    device_id: self.device_id.unwrap_or_else(ruma::DeviceId::new),
+                    device_id,
                 },
                 tokens: SessionTokens {
-                    access_token: self.service.config().appservice_token(),
+                    access_token: self.service.config().appservice_token().into_inner(),
                     refresh_token: None,
                 },
             }
@@ -160,6 +217,7 @@ impl VirtualClientBuilder {
             localpart: self.localpart.clone(),
             service: self.service.clone(),
             client: internal_client,
+            http_client,
             kind: client_kind,
         };
         self.service.store_client(output.clone());
@@ -173,6 +231,7 @@ pub struct VirtualClient {
     pub(crate) localpart: String,
     pub(crate) service: crate::Appservice,
     pub(crate) client: Client,
+    pub(crate) http_client: reqwest::Client,
     pub(crate) kind: VirtualClientKind,
 }
 
@@ -194,6 +253,414 @@ impl VirtualClient {
     pub fn kind(&self) -> VirtualClientKind {
         self.kind.clone()
     }
+
+    /// Sends a request like [Client::send], tagged with the current span so the proxy's logs can be tied back to the caller,
+    /// e.g. the inbound transaction being handled. matrix-sdk can't add headers to its own requests, so requests made through
+    /// [Client::send] or higher-level APIs (`room.send(..)`, `whoami()`, ..) aren't tagged. Request configs (timeouts, retries) don't apply.
+    pub async fn send_traced<R>(&self, request: R) -> matrix_sdk::HttpResult<R::IncomingResponse>
+    where
+        R: OutgoingRequest + Debug,
+        matrix_sdk::HttpError: From<FromHttpResponseError<R::EndpointError>>,
+    {
+        let access_token = self.client.access_token();
+        let access_token = match access_token.as_deref() {
+            Some(token) => SendAccessToken::IfRequired(token),
+            None => SendAccessToken::None,
+        };
+        let request = request
+            .try_into_http_request::<Vec<u8>>(self.client.homeserver().as_str(), access_token, &self.client.supported_versions().await?)
+            .or_else(|e| Err(matrix_sdk::HttpError::IntoHttp(e)))?;
+        let request = reqwest::RequestBuilder::from_parts(self.http_client.clone(), reqwest::Request::try_from(request)?);
+        let response = traced(request).send().await?;
+
+        let mut builder = axum::http::Response::builder().status(response.status()).version(response.version());
+        if let Some(headers) = builder.headers_mut() {
+            headers.extend(response.headers().clone());
+        }
+        let response = builder.body(response.bytes().await?).expect("Response parts were taken from a valid response");
+        Ok(R::IncomingResponse::try_from_http_response(response)?)
+    }
+
+    /// Sends the following events with the appservice-only `ts` parameter, e.g. to import history with its original timestamps
+    pub fn with_timestamp(&self, ts: MilliSecondsSinceUnixEpoch) -> TimestampedClient<'_> {
+        TimestampedClient { client: self, ts }
+    }
+
+    /// Sends a batch of historical message-like events in order, each with its own origin timestamp. Stops at the first failure.
+    pub async fn send_history<C: MessageLikeEventContent>(
+        &self,
+        room_id: &ruma::RoomId,
+        events: impl IntoIterator<Item = (MilliSecondsSinceUnixEpoch, C)>
+    ) -> crate::Result<Vec<ruma::OwnedEventId>> {
+        let mut event_ids = Vec::new();
+        for (ts, content) in events {
+            event_ids.push(self.with_timestamp(ts).send(room_id, content).await?.event_id);
+        }
+
+        Ok(event_ids)
+    }
+
+    /// Creates (or renames) a device for this user without logging in (MSC4190), e.g. to set up encryption.
+    /// Defaults to the device this client acts as.
+    pub async fn create_device(
+        &self,
+        device_id: Option<ruma::OwnedDeviceId>,
+        display_name: Option<String>
+    ) -> crate::Result<ruma::OwnedDeviceId> {
+        let device_id = match device_id {
+            Some(device_id) => device_id,
+            None => self.client.device_id().map(|v| v.to_owned()).ok_or_else(|| crate::Error::UnregisteredUser(self.localpart()))?,
+        };
+        let path = format!("/_matrix/client/v3/devices/{device_id}");
+        let user_id = self.client.user_id().map(|v| v.to_string()).ok_or_else(|| crate::Error::UnregisteredUser(self.localpart()))?;
+
+        // The device can't be masqueraded as before it exists
+        self.add_proxy_directive(&path, ProxyDirective::UserId(user_id));
+        let request = ruma::assign!(update_device::v3::Request::new(device_id.clone()), { display_name });
+        self.send_traced(request).await?;
+        Ok(device_id)
+    }
+
+    /// Lists the devices of this user
+    pub async fn devices(&self) -> crate::Result<Vec<Device>> {
+        Ok(self.send_traced(get_devices::v3::Request::new()).await?.devices)
+    }
+
+    /// URL of a homeserver endpoint, as reached through the internal proxy
+    fn endpoint(&self, path: impl AsRef<str>) -> url::Url {
+        let mut url = self.client.homeserver();
+        let prefixed = format!("{}{}", url.path().trim_end_matches('/'), path.as_ref());
+        url.set_path(&prefixed);
+        url
+    }
+
+    /// Uploads media as this user. The content type is sniffed from the content if not given.
+    pub async fn upload_media(
+        &self,
+        data: impl Into<Bytes>,
+        content_type: Option<&str>,
+        filename: Option<&str>
+    ) -> crate::Result<ruma::OwnedMxcUri> {
+        let data = data.into();
+        let length = data.len() as u64;
+        let stream = futures_util::stream::once(async move { Ok::<_, crate::Error>(data) });
+        self.upload_media_stream(stream, Some(length), content_type, filename).await
+    }
+
+    /// Uploads media as this user, streamed to the homeserver without buffering.
+    /// The content type is sniffed from the first chunk if not given. Fails if the content exceeds [MediaPolicy::max_upload_size](crate::types::MediaPolicy::max_upload_size).
+    #[tracing::instrument(skip_all, fields(localpart = %self.localpart, content_length))]
+    pub async fn upload_media_stream<S, E: 'static>(
+        &self,
+        stream: S,
+        content_length: Option<u64>,
+        content_type: Option<&str>,
+        filename: Option<&str>
+    ) -> crate::Result<ruma::OwnedMxcUri>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+        crate::Error: From<E>,
+    {
+        let limit = self.service.config().media().max_upload_size;
+        if let Some(length) = content_length {
+            tracing::Span::current().record("content_length", length);
+            if length > limit {
+                return Err(crate::Error::MediaTooLarge(limit));
+            }
+        }
+
+        let (sniffed, stream) = media::sniff(media::limited(stream, limit)).await?;
+        let content_type = content_type.map(|v| v.to_string()).or(sniffed).unwrap_or_else(|| media::DEFAULT_CONTENT_TYPE.to_string());
+
+        let mut url = self.endpoint("/_matrix/media/v3/upload");
+        if let Some(filename) = filename {
+            url.query_pairs_mut().append_pair("filename", filename);
+        }
+        let mut request = traced(self.http_client.post(url))
+            .header(reqwest::header::CONTENT_TYPE, content_type)
+            .body(reqwest::Body::wrap_stream(stream));
+        if let Some(length) = content_length {
+            request = request.header(reqwest::header::CONTENT_LENGTH, length);
+        }
+
+        #[derive(Deserialize)]
+        struct UploadResponse {
+            content_uri: ruma::OwnedMxcUri,
+        }
+        let response = request.send().await?.error_for_status()?.json::<UploadResponse>().await?;
+        Ok(response.content_uri)
+    }
+
+    /// Downloads media via the authenticated media endpoints, streamed as it arrives.
+    /// Fails if the content exceeds [MediaPolicy::max_download_size](crate::types::MediaPolicy::max_download_size).
+    #[tracing::instrument(skip_all, fields(localpart = %self.localpart, uri = %uri))]
+    pub async fn download_media(&self, uri: &ruma::MxcUri) -> crate::Result<MediaDownload> {
+        let (server_name, media_id) = uri.parts().or_else(|e| Err(crate::Error::Unknown(anyhow::Error::from(e))))?;
+        let limit = self.service.config().media().max_download_size;
+
+        let response = traced(self.http_client.get(self.endpoint(format!("/_matrix/client/v1/media/download/{server_name}/{media_id}"))))
+            .send()
+            .await?
+            .error_for_status()?;
+        let content_length = response.content_length();
+        if content_length.is_some_and(|length| length > limit) {
+            return Err(crate::Error::MediaTooLarge(limit));
+        }
+
+        let reported = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let stream = media::limited(response.bytes_stream(), limit);
+        let (content_type, stream) = match reported {
+            Some(content_type) => (content_type, stream.boxed()),
+            None => {
+                let (sniffed, stream) = media::sniff(stream).await?;
+                (sniffed.unwrap_or_else(|| media::DEFAULT_CONTENT_TYPE.to_string()), stream)
+            }
+        };
+
+        Ok(MediaDownload { content_type, content_length, stream })
+    }
+
+    /// Whether `uri` can still be downloaded from the homeserver
+    pub async fn media_exists(&self, uri: &ruma::MxcUri) -> crate::Result<bool> {
+        let (server_name, media_id) = uri.parts().or_else(|e| Err(crate::Error::Unknown(anyhow::Error::from(e))))?;
+        // The body is never read, dropping the response aborts the transfer
+        let response = traced(self.http_client.get(self.endpoint(format!("/_matrix/client/v1/media/download/{server_name}/{media_id}"))))
+            .send()
+            .await?;
+
+        match response.status() {
+            reqwest::StatusCode::NOT_FOUND => Ok(false),
+            _ => response.error_for_status().map(|_| true).or_else(|e| Err(crate::Error::Reqwest(e))),
+        }
+    }
+
+    /// A usable upload cache entry for `hash`, marking it as used. Expired entries & (with [MediaPolicy::cache_verify](crate::types::MediaPolicy::cache_verify)) vanished media are dropped.
+    async fn cached_media(&self, hash: &str) -> crate::Result<Option<MediaCacheEntry>> {
+        let policy = self.service.config().media();
+        let cache = self.service.state_media_cache()?;
+        let Some(mut entry) = cache.get(hash)? else {
+            return Ok(None);
+        };
+
+        if entry.is_expired(policy.cache_max_age_secs) || (policy.cache_verify && !self.media_exists(&entry.uri).await?) {
+            tracing::debug!(uri = %entry.uri, "Dropping stale media cache entry");
+            let _ = cache.remove(hash)?;
+            return Ok(None);
+        }
+
+        entry.last_used = chrono::Utc::now();
+        let _ = cache.insert(hash, entry.clone())?;
+        Ok(Some(entry))
+    }
+
+    /// Uploads media as this user, unless identical content was uploaded before, in which case the previous URI is returned
+    pub async fn upload_cached(
+        &self,
+        data: impl Into<Bytes>,
+        content_type: Option<&str>,
+        filename: Option<&str>
+    ) -> crate::Result<ruma::OwnedMxcUri> {
+        let data = data.into();
+        let hash = media::content_hash(&data);
+        if let Some(entry) = self.cached_media(&hash).await? {
+            tracing::trace!(uri = %entry.uri, "Reusing cached upload");
+            return Ok(entry.uri);
+        }
+
+        let content_type = content_type
+            .map(|v| v.to_string())
+            .or_else(|| infer::get(&data).map(|kind| kind.mime_type().to_string()))
+            .unwrap_or_else(|| media::DEFAULT_CONTENT_TYPE.to_string());
+        let size = data.len() as u64;
+        let uri = self.upload_media(data, Some(&content_type), filename).await?;
+
+        let now = chrono::Utc::now();
+        let cache = self.service.state_media_cache()?;
+        if cache.insert(&hash, MediaCacheEntry { uri: uri.clone(), content_type, size, uploaded_at: now, last_used: now })?.is_none() {
+            self.service.media_cache_inserted()?;
+        }
+
+        Ok(uri)
+    }
+
+    /// The previously uploaded URI for media fetched from `source` (e.g. a remote avatar URL), so it doesn't need to be fetched again
+    pub async fn cached_source(&self, source: impl AsRef<str>) -> crate::Result<Option<ruma::OwnedMxcUri>> {
+        let Some(hash) = self.service.state_media_sources()?.get(source.as_ref())? else {
+            return Ok(None);
+        };
+
+        Ok(self.cached_media(&hash).await?.map(|entry| entry.uri))
+    }
+
+    /// Like [VirtualClient::upload_cached], also remembering the content under `source` for [VirtualClient::cached_source]
+    pub async fn upload_cached_source(
+        &self,
+        source: impl AsRef<str>,
+        data: impl Into<Bytes>,
+        content_type: Option<&str>,
+        filename: Option<&str>
+    ) -> crate::Result<ruma::OwnedMxcUri> {
+        let data = data.into();
+        let hash = media::content_hash(&data);
+        let uri = self.upload_cached(data, content_type, filename).await?;
+        let _ = self.service.state_media_sources()?.insert(source.as_ref(), hash)?;
+        Ok(uri)
+    }
+
+    /// The bot's persisted record, which the service user doesn't have
+    fn user_record(&self) -> crate::Result<UserRecord> {
+        self.service
+            .state_user_records()?
+            .get(self.localpart())?
+            .ok_or_else(|| crate::Error::UnregisteredUser(self.localpart()))
+    }
+
+    fn owned_user_id(&self) -> crate::Result<ruma::OwnedUserId> {
+        self.client.user_id().map(|v| v.to_owned()).ok_or_else(|| crate::Error::UnregisteredUser(self.localpart()))
+    }
+
+    /// Sets this bot's global display name & avatar (`None` clears them), skipping whatever already matches the last applied values.
+    /// Returns whether anything was sent to the homeserver.
+    #[tracing::instrument(skip_all, fields(localpart = %self.localpart))]
+    pub async fn set_profile(&self, displayname: Option<&str>, avatar_url: Option<&ruma::MxcUri>) -> crate::Result<bool> {
+        let mut record = self.user_record()?;
+        let applied = record.profile();
+        let wanted = Profile::new(displayname, avatar_url.map(|v| v.as_str()));
+        if applied == wanted {
+            tracing::trace!("Profile unchanged");
+            return Ok(false);
+        }
+
+        let user_id = self.owned_user_id()?;
+        if applied.displayname != wanted.displayname {
+            self.send_traced(set_display_name::v3::Request::new(user_id.clone(), wanted.displayname.clone())).await?;
+        }
+        if applied.avatar_url != wanted.avatar_url {
+            self.send_traced(set_avatar_url::v3::Request::new(user_id, avatar_url.map(|v| v.to_owned()))).await?;
+        }
+
+        record.set_profile(wanted);
+        let _ = self.service.state_user_records()?.insert(self.localpart(), record)?;
+        Ok(true)
+    }
+
+    /// Overrides this bot's display name & avatar in one room through its member event, unless they already match the last applied override.
+    /// The rest of the member event is kept as is. Fails with [Error::NotJoined](crate::Error::NotJoined) unless the bot is joined to the room.
+    /// Overrides are forgotten (and have to be set again) whenever the global profile changes, as the homeserver replaces them.
+    /// Returns whether anything was sent to the homeserver.
+    #[tracing::instrument(skip_all, fields(localpart = %self.localpart, room_id = %room_id))]
+    pub async fn set_room_profile(
+        &self,
+        room_id: &ruma::RoomId,
+        displayname: Option<&str>,
+        avatar_url: Option<&ruma::MxcUri>
+    ) -> crate::Result<bool> {
+        let mut record = self.user_record()?;
+        let wanted = Profile::new(displayname, avatar_url.map(|v| v.as_str()));
+        if record.room_profiles().get(room_id.as_str()) == Some(&wanted) {
+            tracing::trace!("Room profile unchanged");
+            return Ok(false);
+        }
+
+        // Updating the member event of a room the bot isn't in would join it
+        let user_id = self.owned_user_id()?;
+        let request = get_state_event_for_key::v3::Request::new(room_id.to_owned(), StateEventType::RoomMember, user_id.to_string());
+        let mut content = match self.send_traced(request).await {
+            Ok(response) => serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(response.event_or_content.get())?,
+            Err(e) if matches!(e.client_api_error_kind(), Some(ErrorKind::NotFound)) => serde_json::Map::new(),
+            Err(e) => return Err(e.into()),
+        };
+        if content.get("membership").and_then(|v| v.as_str()) != Some("join") {
+            return Err(crate::Error::NotJoined(room_id.to_string()));
+        }
+
+        for (key, value) in [("displayname", &wanted.displayname), ("avatar_url", &wanted.avatar_url)] {
+            let _ = match value {
+                Some(value) => content.insert(key.to_string(), value.clone().into()),
+                None => content.remove(key),
+            };
+        }
+        let body = Raw::from_json(serde_json::value::to_raw_value(&content)?);
+        let request = send_state_event::v3::Request::new_raw(room_id.to_owned(), StateEventType::RoomMember, user_id.to_string(), body);
+        self.send_traced(request).await?;
+
+        record.set_room_profile(room_id.as_str(), wanted);
+        let _ = self.service.state_user_records()?.insert(self.localpart(), record)?;
+        Ok(true)
+    }
+
+    /// The [ProxyDirectiveTarget] for a request this client makes to `path`
+    pub fn proxy_directive_target(&self, path: impl AsRef<str>) -> ProxyDirectiveTarget {
+        match self.kind {
+            VirtualClientKind::Service => ProxyDirectiveTarget::service(path),
+            VirtualClientKind::Bot => ProxyDirectiveTarget::bot(path, self.localpart()),
+        }
+    }
+
+    /// Attaches a one-shot [ProxyDirective] to the next request this client makes to `path`
+    pub fn add_proxy_directive(&self, path: impl AsRef<str>, directive: ProxyDirective) -> () {
+        self.add_proxy_directives(path, [directive]);
+    }
+
+    /// Attaches one-shot [ProxyDirective]s to the next request this client makes to `path`, all applied to that same request
+    pub fn add_proxy_directives(&self, path: impl AsRef<str>, directives: impl IntoIterator<Item = ProxyDirective>) -> () {
+        self.service.add_proxy_directives(self.proxy_directive_target(path), directives);
+    }
+}
+
+/// A view of a [`VirtualClient`] that sends events with a fixed origin timestamp (appservice `ts` parameter)
+#[derive(Clone, Debug)]
+pub struct TimestampedClient<'a> {
+    client: &'a VirtualClient,
+    ts: MilliSecondsSinceUnixEpoch,
+}
+
+impl<'a> TimestampedClient<'a> {
+    /// The timestamp events are sent with
+    pub fn timestamp(&self) -> MilliSecondsSinceUnixEpoch {
+        self.ts
+    }
+
+    /// Sends a message-like event into a room
+    pub async fn send(
+        &self,
+        room_id: &ruma::RoomId,
+        content: impl MessageLikeEventContent
+    ) -> crate::Result<send_message_event::v3::Response> {
+        let txn_id = ruma::TransactionId::new();
+        let event_type = content.event_type();
+        let body = Raw::from_json(serde_json::value::to_raw_value(&content)?);
+
+        self.client.add_proxy_directive(
+            format!("/_matrix/client/v3/rooms/{room_id}/send/{event_type}/{txn_id}"),
+            ProxyDirective::Timestamp(self.ts)
+        );
+        let request = send_message_event::v3::Request::new_raw(room_id.to_owned(), txn_id, event_type, body);
+        Ok(self.client.send(request).await?)
+    }
+
+    /// Sends a state event into a room
+    pub async fn send_state(
+        &self,
+        room_id: &ruma::RoomId,
+        state_key: impl Into<String>,
+        content: impl StateEventContent
+    ) -> crate::Result<send_state_event::v3::Response> {
+        let state_key = state_key.into();
+        let event_type = content.event_type();
+        let body = Raw::from_json(serde_json::value::to_raw_value(&content)?);
+
+        self.client.add_proxy_directive(
+            format!("/_matrix/client/v3/rooms/{room_id}/state/{event_type}/{state_key}"),
+            ProxyDirective::Timestamp(self.ts)
+        );
+        let request = send_state_event::v3::Request::new_raw(room_id.to_owned(), event_type, state_key, body);
+        Ok(self.client.send(request).await?)
+    }
 }
 
 impl Deref for VirtualClient {